@@ -0,0 +1,286 @@
+//! Integer geometry for locating parking lots, coordinates are micro-degrees.
+
+use codec::{Decode, Encode};
+use rstd::{cmp, prelude::*, result};
+use sr_primitives::traits::IntegerSquareRoot;
+
+/// Height of a band of the location index in micro-degrees, about 28 km of latitude. Every band is split
+/// into cells about as wide as they are high, so a box of the same size overlaps a few cells at any latitude
+pub const CELL_SIZE: i32 = 250_000;
+
+/// Millimeters of one micro-degree of latitude is `MM_PER_DEGREE / 1_000_000`
+const MM_PER_DEGREE: i64 = 111_195_080;
+
+/// Micro-degrees of one degree
+pub const MICRO_DEGREES: i64 = 1_000_000;
+
+/// cos of every whole degree from 0 to 90, scaled by 10_000
+const COS_TABLE: [i64; 91] = [
+    10000, 9998, 9994, 9986, 9976, 9962, 9945, 9925, 9903, 9877, //
+    9848, 9816, 9781, 9744, 9703, 9659, 9613, 9563, 9511, 9455, //
+    9397, 9336, 9272, 9205, 9135, 9063, 8988, 8910, 8829, 8746, //
+    8660, 8572, 8480, 8387, 8290, 8192, 8090, 7986, 7880, 7771, //
+    7660, 7547, 7431, 7314, 7193, 7071, 6947, 6820, 6691, 6561, //
+    6428, 6293, 6157, 6018, 5878, 5736, 5592, 5446, 5299, 5150, //
+    5000, 4848, 4695, 4540, 4384, 4226, 4067, 3907, 3746, 3584, //
+    3420, 3256, 3090, 2924, 2756, 2588, 2419, 2250, 2079, 1908, //
+    1736, 1564, 1392, 1219, 1045, 872, 698, 523, 349, 175, //
+    0,
+];
+
+/// cos of the latitude scaled by 10_000, interpolated between whole degrees
+fn cos_e4(latitude: i32) -> i64 {
+    let latitude = cmp::min(i64::from(latitude).abs(), 90 * MICRO_DEGREES);
+    let degree = (latitude / MICRO_DEGREES) as usize;
+    if degree == 90 {
+        return 0;
+    }
+    let fraction = latitude % MICRO_DEGREES;
+    COS_TABLE[degree] - (COS_TABLE[degree] - COS_TABLE[degree + 1]) * fraction / MICRO_DEGREES
+}
+
+fn clamp(value: i64, limit: i64) -> i64 {
+    cmp::max(cmp::min(value, limit), -limit)
+}
+
+fn div_floor(value: i32, divisor: i32) -> i32 {
+    if value >= 0 {
+        value / divisor
+    } else {
+        (value - divisor + 1) / divisor
+    }
+}
+
+/// Number of cells in a band of the location index, counted at the edge of the band closer to the equator
+/// where the band is the widest. A band touching a pole is a single cell
+fn band_cells(band: i32) -> i32 {
+    let latitude = if band >= 0 { band * CELL_SIZE } else { (band + 1) * CELL_SIZE };
+    cmp::max(1, 360 * MICRO_DEGREES * cos_e4(latitude) / 10_000 / i64::from(CELL_SIZE)) as i32
+}
+
+/// The cell of the band containing the longitude, counted eastwards from the antimeridian
+fn longitude_cell(band: i32, longitude: i32) -> i32 {
+    let cells = band_cells(band);
+    let cell = (i64::from(longitude) + 180 * MICRO_DEGREES) * i64::from(cells) / (360 * MICRO_DEGREES);
+    cmp::min(cell as i32, cells - 1)
+}
+
+/// GeoPoint is a point on the earth in micro-degrees, i.e. millionths of a degree,
+/// e.g. `GeoPoint { latitude: 31_247_538, longitude: 121_489_559 }` is 31.247538°N 121.489559°E
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GeoPoint {
+    /// From `-MAX_LATITUDE` (south pole) to `MAX_LATITUDE` (north pole)
+    pub latitude: i32,
+    /// From `-MAX_LONGITUDE` (west) to `MAX_LONGITUDE` (east)
+    pub longitude: i32,
+}
+
+/// The largest latitude in micro-degrees
+pub const MAX_LATITUDE: i32 = 90_000_000;
+
+/// The largest longitude in micro-degrees
+pub const MAX_LONGITUDE: i32 = 180_000_000;
+
+impl GeoPoint {
+    /// A point checked to be on the earth
+    pub fn new(latitude: i32, longitude: i32) -> result::Result<Self, &'static str> {
+        let point = GeoPoint { latitude, longitude };
+        if point.is_valid() {
+            Ok(point)
+        } else {
+            Err("Location is out of range")
+        }
+    }
+
+    /// Whether the latitude and the longitude are in range
+    pub fn is_valid(&self) -> bool {
+        self.latitude.abs() <= MAX_LATITUDE && self.longitude.abs() <= MAX_LONGITUDE
+    }
+
+    /// The cell of the location index containing the point, as the band and the cell in the band
+    pub fn cell(&self) -> (i32, i32) {
+        let band = div_floor(self.latitude, CELL_SIZE);
+        (band, longitude_cell(band, self.longitude))
+    }
+
+    /// Distance in meters to another point, with the equirectangular approximation which is
+    /// accurate enough for the distances people walk from a parking lot
+    pub fn distance(&self, other: &GeoPoint) -> u64 {
+        let d_latitude = (i64::from(self.latitude) - i64::from(other.latitude)).abs();
+        let mut d_longitude = (i64::from(self.longitude) - i64::from(other.longitude)).abs();
+        // the shorter way around the antimeridian
+        if d_longitude > 180 * MICRO_DEGREES {
+            d_longitude = 360 * MICRO_DEGREES - d_longitude;
+        }
+
+        let middle = ((i64::from(self.latitude) + i64::from(other.latitude)) / 2) as i32;
+        let y = d_latitude * MM_PER_DEGREE / MICRO_DEGREES;
+        let x = d_longitude * MM_PER_DEGREE / MICRO_DEGREES * cos_e4(middle) / 10_000;
+        let squared = (x as u128) * (x as u128) + (y as u128) * (y as u128);
+        (squared.integer_sqrt() / 1000) as u64
+    }
+
+    /// Whether the point is inside the bounding box
+    pub fn within(&self, bbox: &BoundingBox) -> bool {
+        bbox.contains(self)
+    }
+}
+
+/// BoundingBox is the area between two corners, it crosses the antimeridian
+/// if the west longitude is greater than the east one
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BoundingBox {
+    pub south_west: GeoPoint,
+    pub north_east: GeoPoint,
+}
+
+impl BoundingBox {
+    /// The smallest box containing every point within `radius` meters of the center, clamped at the poles
+    pub fn around(center: &GeoPoint, radius: u32) -> Self {
+        let radius_mm = i64::from(radius) * 1000;
+        let d_latitude = radius_mm * MICRO_DEGREES / MM_PER_DEGREE + 1;
+        // longitude degrees shrink towards the poles, take the latitude of the edge closer to the pole
+        let edge = cmp::min(i64::from(center.latitude).abs() + d_latitude, 90 * MICRO_DEGREES) as i32;
+        let cos = cos_e4(edge);
+        let d_longitude = if cos == 0 {
+            180 * MICRO_DEGREES
+        } else {
+            cmp::min(d_latitude * 10_000 / cos + 1, 180 * MICRO_DEGREES)
+        };
+
+        let latitude = i64::from(center.latitude);
+        let longitude = i64::from(center.longitude);
+        let (west, east) = if d_longitude >= 180 * MICRO_DEGREES {
+            (-MAX_LONGITUDE, MAX_LONGITUDE)
+        } else {
+            (wrap_longitude(longitude - d_longitude), wrap_longitude(longitude + d_longitude))
+        };
+        BoundingBox {
+            south_west: GeoPoint {
+                latitude: clamp(latitude - d_latitude, 90 * MICRO_DEGREES) as i32,
+                longitude: west,
+            },
+            north_east: GeoPoint {
+                latitude: clamp(latitude + d_latitude, 90 * MICRO_DEGREES) as i32,
+                longitude: east,
+            },
+        }
+    }
+
+    /// Whether the box crosses the antimeridian
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.south_west.longitude > self.north_east.longitude
+    }
+
+    /// Whether the point is inside the box, edges included
+    pub fn contains(&self, point: &GeoPoint) -> bool {
+        let in_latitude = self.south_west.latitude <= point.latitude && point.latitude <= self.north_east.latitude;
+        let in_longitude = if self.crosses_antimeridian() {
+            self.south_west.longitude <= point.longitude || point.longitude <= self.north_east.longitude
+        } else {
+            self.south_west.longitude <= point.longitude && point.longitude <= self.north_east.longitude
+        };
+        in_latitude && in_longitude
+    }
+
+    /// All cells of the location index overlapping the box
+    pub fn cells(&self) -> Vec<(i32, i32)> {
+        let min_band = div_floor(self.south_west.latitude, CELL_SIZE);
+        let max_band = div_floor(self.north_east.latitude, CELL_SIZE);
+        (min_band..=max_band)
+            .flat_map(|band| {
+                let west_cell = longitude_cell(band, self.south_west.longitude);
+                let east_cell = longitude_cell(band, self.north_east.longitude);
+                let cells = if self.crosses_antimeridian() {
+                    (west_cell..band_cells(band)).chain(0..=east_cell).collect::<Vec<_>>()
+                } else {
+                    (west_cell..=east_cell).collect::<Vec<_>>()
+                };
+                cells.into_iter().map(move |cell| (band, cell))
+            })
+            .collect()
+    }
+}
+
+fn wrap_longitude(longitude: i64) -> i32 {
+    if longitude > 180 * MICRO_DEGREES {
+        (longitude - 360 * MICRO_DEGREES) as i32
+    } else if longitude < -180 * MICRO_DEGREES {
+        (longitude + 360 * MICRO_DEGREES) as i32
+    } else {
+        longitude as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(latitude: i32, longitude: i32) -> GeoPoint {
+        GeoPoint::new(latitude, longitude).unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        assert!(GeoPoint::new(MAX_LATITUDE, -MAX_LONGITUDE).is_ok());
+        assert_eq!(GeoPoint::new(MAX_LATITUDE + 1, 0), Err("Location is out of range"));
+        assert_eq!(GeoPoint::new(0, -MAX_LONGITUDE - 1), Err("Location is out of range"));
+    }
+
+    #[test]
+    fn test_cell() {
+        assert_eq!(point(31_247_538, 121_489_559).cell(), (124, 1033));
+        // 1440 cells of a quarter degree at the equator, counted from the antimeridian
+        assert_eq!(point(0, 249_999).cell(), (0, 720));
+        assert_eq!(point(-1, -1).cell(), (-1, 719));
+        assert_eq!(point(-250_001, 250_000).cell(), (-2, 721));
+        // the bands touching the poles are single cells
+        assert_eq!(point(MAX_LATITUDE, MAX_LONGITUDE).cell(), (360, 0));
+        assert_eq!(point(-MAX_LATITUDE, -MAX_LONGITUDE).cell(), (-360, 0));
+    }
+
+    #[test]
+    fn test_distance() {
+        let center = point(31_247_538, 121_489_559);
+        assert_eq!(center.distance(&center), 0);
+        // one degree of latitude
+        assert_eq!(point(0, 0).distance(&point(1_000_000, 0)), 111_195);
+        // one degree of longitude at 60 degrees is half as long
+        assert_eq!(point(60_000_000, 0).distance(&point(60_000_000, 1_000_000)), 55_597);
+        assert_eq!(point(0, 179_500_000).distance(&point(0, -179_500_000)), 111_195);
+    }
+
+    #[test]
+    fn test_bounding_box() {
+        let center = point(31_247_538, 121_489_559);
+        let bbox = BoundingBox::around(&center, 500);
+        assert!(center.within(&bbox));
+        assert!(point(31_251_000, 121_494_000).within(&bbox));
+        assert!(!point(31_260_000, 121_489_559).within(&bbox));
+        assert_eq!(bbox.cells().len(), 2);
+        assert!(bbox.cells().contains(&center.cell()));
+
+        let bbox = BoundingBox::around(&point(0, 179_999_000), 1000);
+        assert!(bbox.crosses_antimeridian());
+        assert!(point(0, -179_999_000).within(&bbox));
+        assert!(!point(0, 0).within(&bbox));
+        assert!(bbox.cells().contains(&point(0, -179_999_000).cell()));
+    }
+
+    #[test]
+    fn test_cells_at_poles() {
+        // the cells narrow in degrees towards the poles, a box of the same size overlaps about as many of them
+        assert_eq!(BoundingBox::around(&point(0, 0), 50_000).cells().len(), 16);
+        assert_eq!(BoundingBox::around(&point(60_000_000, 0), 50_000).cells().len(), 17);
+
+        // the box around a point near the pole spans every longitude
+        let center = point(89_900_000, 0);
+        let bbox = BoundingBox::around(&center, 50_000);
+        assert_eq!((bbox.south_west.longitude, bbox.north_east.longitude), (-MAX_LONGITUDE, MAX_LONGITUDE));
+        assert_eq!(bbox.cells().len(), 38);
+        assert!(bbox.cells().contains(&point(89_950_000, 179_000_000).cell()));
+        assert_eq!(BoundingBox::around(&point(-MAX_LATITUDE, 0), 50_000).cells().len(), 18);
+    }
+}
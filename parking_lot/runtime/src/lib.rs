@@ -8,12 +8,13 @@
 #[cfg(feature = "std")]
 include!(concat!(env!("OUT_DIR"), "/wasm_binary.rs"));
 
-mod parking;
+pub mod geo;
+pub mod parking;
 
 use babe::AuthorityId as BabeId;
 use client::{
     block_builder::api::{self as block_builder_api, CheckInherentsResult, InherentData},
-    impl_runtime_apis, runtime_api as client_api,
+    decl_runtime_apis, impl_runtime_apis, runtime_api as client_api,
 };
 use grandpa::fg_primitives::{self, ScheduledChange};
 use grandpa::{AuthorityId as GrandpaId, AuthorityWeight as GrandpaWeight};
@@ -59,6 +60,9 @@ pub type Index = u32;
 /// A hash of some data used by the chain.
 pub type Hash = primitives::H256;
 
+/// Timestamp in milliseconds.
+pub type Moment = u64;
+
 /// Digest item type.
 pub type DigestItem = generic::DigestItem<Hash>;
 
@@ -95,8 +99,10 @@ pub const VERSION: RuntimeVersion = RuntimeVersion {
     spec_name: create_runtime_str!("wx-node"),
     impl_name: create_runtime_str!("wx-node"),
     authoring_version: 3,
-    spec_version: 4,
-    impl_version: 4,
+    // 5 changed the parking calls and the encoding of the parking storage without a migration,
+    // chains of an older version have to be purged, see the README.
+    spec_version: 5,
+    impl_version: 5,
     apis: RUNTIME_API_VERSIONS,
 };
 
@@ -211,7 +217,7 @@ parameter_types! {
 
 impl timestamp::Trait for Runtime {
     /// A timestamp: milliseconds since the unix epoch.
-    type Moment = u64;
+    type Moment = Moment;
     type OnTimestampSet = Babe;
     type MinimumPeriod = MinimumPeriod;
 }
@@ -250,9 +256,21 @@ impl sudo::Trait for Runtime {
     type Proposal = Call;
 }
 
+parameter_types! {
+    pub const EnteringDeposit: Balance = 1_000;
+    pub const ReservationDeposit: Balance = 1_000;
+    pub const MaxReservationPeriod: u64 = 2 * 60 * 60 * 1000;
+    pub const ParkingCommission: Permill = Permill::from_percent(5);
+}
+
 impl parking::Trait for Runtime {
     type Event = Event;
     type Currency = Balances;
+    type PricingModel = parking::DefaultPricing;
+    type EnteringDeposit = EnteringDeposit;
+    type ReservationDeposit = ReservationDeposit;
+    type MaxReservationPeriod = MaxReservationPeriod;
+    type Commission = ParkingCommission;
 }
 
 construct_runtime!(
@@ -298,6 +316,41 @@ pub type CheckedExtrinsic = generic::CheckedExtrinsic<AccountId, Call, SignedExt
 /// Executive: handles dispatch to the various modules.
 pub type Executive = executive::Executive<Runtime, Block, system::ChainContext<Runtime>, Runtime, AllModules>;
 
+pub use geo::GeoPoint;
+
+/// Parking lot type of the runtime.
+pub type ParkingLot = parking::ParkingLot<Runtime>;
+/// Parking info type of the runtime.
+pub type ParkingInfo = parking::ParkingInfo<Runtime>;
+
+decl_runtime_apis! {
+    /// Queries of the parking module, so that clients do not need to decode the raw storage.
+    pub trait ParkingApi {
+        /// Parking lots in the order of their indexes, starting from `offset` and at most `limit` of them.
+        fn parking_lots(offset: u64, limit: u64) -> Vec<(Hash, ParkingLot)>;
+        /// The parking lot of the hash.
+        fn parking_lot(parking_lot_hash: Hash) -> Option<ParkingLot>;
+        /// All parking lots of the owner.
+        fn owner_parking_lots(owner: AccountId) -> Vec<(Hash, ParkingLot)>;
+        /// The parking info of the user with the fee accrued until `now`.
+        fn parking_session(user: AccountId, now: Moment) -> Option<ParkingInfo>;
+        /// Time of the block, i.e. the time of `timestamp` when it was built.
+        fn now() -> Moment;
+        /// Fee of parking `seconds` in a position of the category if entering now, charged as leaving would.
+        /// Nothing if the positions of the category are full.
+        fn quote_fee(parking_lot_hash: Hash, category: parking::SpotCategory, seconds: u64) -> Option<Balance>;
+        /// Parking lots within `radius` meters of the center with their distances, sorted by the distance.
+        /// Only lots with at least `min_remain` positions and a current price not above `max_price` are returned.
+        fn lots_near(
+            center: GeoPoint,
+            radius: u32,
+            limit: u32,
+            min_remain: u32,
+            max_price: Option<Balance>,
+        ) -> Vec<(Hash, ParkingLot, u64)>;
+    }
+}
+
 impl_runtime_apis! {
     impl client_api::Core<Block> for Runtime {
         fn version() -> RuntimeVersion {
@@ -397,6 +450,42 @@ impl_runtime_apis! {
         }
     }
 
+    impl ParkingApi<Block> for Runtime {
+        fn parking_lots(offset: u64, limit: u64) -> Vec<(Hash, ParkingLot)> {
+            Parking::parking_lots_page(offset, limit)
+        }
+
+        fn parking_lot(parking_lot_hash: Hash) -> Option<ParkingLot> {
+            Parking::parking_lots(parking_lot_hash)
+        }
+
+        fn owner_parking_lots(owner: AccountId) -> Vec<(Hash, ParkingLot)> {
+            Parking::owner_parking_lots(&owner)
+        }
+
+        fn parking_session(user: AccountId, now: Moment) -> Option<ParkingInfo> {
+            Parking::parking_session(&user, now)
+        }
+
+        fn now() -> Moment {
+            Timestamp::now()
+        }
+
+        fn quote_fee(parking_lot_hash: Hash, category: parking::SpotCategory, seconds: u64) -> Option<Balance> {
+            Parking::quote_fee(parking_lot_hash, category, seconds)
+        }
+
+        fn lots_near(
+            center: GeoPoint,
+            radius: u32,
+            limit: u32,
+            min_remain: u32,
+            max_price: Option<Balance>,
+        ) -> Vec<(Hash, ParkingLot, u64)> {
+            Parking::lots_near(center, radius, limit, min_remain, max_price)
+        }
+    }
+
     impl substrate_session::SessionKeys<Block> for Runtime {
         fn generate_session_keys(seed: Option<Vec<u8>>) -> Vec<u8> {
             let seed = seed.as_ref().map(|s| rstd::str::from_utf8(&s).expect("Seed is an utf8 string"));
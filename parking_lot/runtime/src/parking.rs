@@ -2,15 +2,23 @@
 #![allow(unused_variables)]
 
 use codec::{Decode, Encode};
-use rstd::{convert::TryInto, prelude::*, result};
-use sr_primitives::traits::{CheckedDiv, CheckedSub, Hash};
+use rstd::{cmp, convert::TryInto, prelude::*, result};
+use sr_primitives::traits::{
+    AccountIdConversion, Bounded, CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, Hash, One, Saturating,
+    SimpleArithmetic, Zero,
+};
+use sr_primitives::{ModuleId, Permill};
 use support::{
-    decl_event, decl_module, decl_storage, dispatch::Result, ensure, traits::Currency, StorageMap, StorageValue,
+    decl_event, decl_module, decl_storage,
+    dispatch::Result,
+    ensure,
+    traits::{Currency, Get, ReservableCurrency},
+    StorageMap, StorageValue,
 };
 
-use log;
+use crate::geo::{self, GeoPoint};
 use rstd::convert::Into;
-use system::ensure_signed;
+use system::{ensure_root, ensure_signed};
 
 
 /// The module's configuration trait.
@@ -18,11 +26,205 @@ pub trait Trait: timestamp::Trait {
     /// The overarching event type.
     type Event: From<Event<Self>> + Into<<Self as system::Trait>::Event>;
     /// The Currency
-    type Currency: Currency<Self::AccountId>;
+    type Currency: ReservableCurrency<Self::AccountId>;
+    /// The deposit reserved when entering, fees are paid from it first at leaving
+    type EnteringDeposit: Get<BalanceOf<Self>>;
+    /// The deposit locked when reserving a position, forfeited to the owner if the user does not arrive
+    type ReservationDeposit: Get<BalanceOf<Self>>;
+    /// The longest time a position can be reserved in advance
+    type MaxReservationPeriod: Get<Self::Moment>;
+    /// The pricing model turning the price curve of a parking lot into a price
+    type PricingModel: PricingModel<BalanceOf<Self>>;
+    /// Share of every payment to an owner taken for the treasury, the sudo key can change it
+    type Commission: Get<Permill>;
 }
 
+/// Id of the module, the treasury account is derived from it
+const MODULE_ID: ModuleId = ModuleId(*b"py/parkg");
+
 type BalanceOf<T> = <<T as Trait>::Currency as Currency<<T as system::Trait>::AccountId>>::Balance;
 
+/// PriceCurve selects how the price of a parking lot follows its occupancy
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub enum PriceCurve<Balance> {
+    /// From min price when empty to max price when full
+    Linear,
+    /// Always min price
+    Flat,
+    /// Price of the highest (occupancy percent, price) tier reached, min price below the first tier
+    Stepped(Vec<(u8, Balance)>),
+    /// Min price until `threshold` percent is occupied, then grows by `growth` percent
+    /// for each more occupied position, up to max price
+    Surge { threshold: u8, growth: u32 },
+}
+
+impl<Balance> Default for PriceCurve<Balance> {
+    fn default() -> Self {
+        PriceCurve::Linear
+    }
+}
+
+/// PricingModel computes the price per second of a parking lot
+pub trait PricingModel<Balance> {
+    /// Check that the curve can be used with the price range of a parking lot
+    fn validate(curve: &PriceCurve<Balance>, min_price: Balance, max_price: Balance) -> Result;
+
+    /// Price per second when `occupied` of `capacity` positions are used
+    fn price(
+        curve: &PriceCurve<Balance>,
+        min_price: Balance,
+        max_price: Balance,
+        capacity: u32,
+        occupied: u32,
+    ) -> result::Result<Balance, &'static str>;
+}
+
+/// DefaultPricing implements all built-in price curves
+pub struct DefaultPricing;
+
+impl<Balance: SimpleArithmetic + Copy> PricingModel<Balance> for DefaultPricing {
+    fn validate(curve: &PriceCurve<Balance>, min_price: Balance, max_price: Balance) -> Result {
+        match curve {
+            PriceCurve::Linear | PriceCurve::Flat => Ok(()),
+            PriceCurve::Stepped(tiers) => {
+                ensure!(!tiers.is_empty(), "Stepped price needs at least one tier");
+                let mut last_threshold = None;
+                for (threshold, price) in tiers.iter() {
+                    ensure!(*threshold <= 100, "Tier threshold must be a percent");
+                    ensure!(
+                        last_threshold.map_or(true, |last| last < *threshold),
+                        "Tier thresholds must be increasing"
+                    );
+                    ensure!(
+                        *price >= min_price && *price <= max_price,
+                        "Tier price must be between min price and max price"
+                    );
+                    last_threshold = Some(*threshold);
+                }
+                Ok(())
+            }
+            PriceCurve::Surge { threshold, growth } => {
+                ensure!(*threshold <= 100, "Surge threshold must be a percent");
+                ensure!(*growth > 0, "Surge growth must be positive");
+                Ok(())
+            }
+        }
+    }
+
+    fn price(
+        curve: &PriceCurve<Balance>,
+        min_price: Balance,
+        max_price: Balance,
+        capacity: u32,
+        occupied: u32,
+    ) -> result::Result<Balance, &'static str> {
+        ensure!(occupied <= capacity, "Remained num greater than capacity");
+        if capacity == 0 {
+            return Ok(min_price);
+        }
+        let percent = (occupied as u64 * 100 / capacity as u64) as u8;
+
+        match curve {
+            PriceCurve::Linear => {
+                let diff_price = max_price
+                    .checked_sub(&min_price)
+                    .ok_or("Max price must be greater than min price")?;
+                diff_price
+                    .checked_mul(&occupied.into())
+                    .ok_or("Mul overflow")?
+                    .checked_div(&capacity.into())
+                    .ok_or("Div overflow")?
+                    .checked_add(&min_price)
+                    .ok_or("Add overflow")
+            }
+            PriceCurve::Flat => Ok(min_price),
+            PriceCurve::Stepped(tiers) => Ok(tiers
+                .iter()
+                .take_while(|(threshold, _)| *threshold <= percent)
+                .last()
+                .map_or(min_price, |(_, price)| *price)),
+            PriceCurve::Surge { threshold, growth } => {
+                let free_num = (capacity as u64 * *threshold as u64 / 100) as u32;
+                let mut price = min_price;
+                for _ in free_num..occupied {
+                    // grow at least one unit so that tiny prices still surge
+                    let step = price
+                        .checked_mul(&(*growth).into())
+                        .map_or(max_price, |step| step / Balance::from(100u32));
+                    let step = if step.is_zero() { One::one() } else { step };
+                    price = price.checked_add(&step).unwrap_or(max_price);
+                    if price >= max_price {
+                        return Ok(max_price);
+                    }
+                }
+                Ok(price)
+            }
+        }
+    }
+}
+
+/// LotStatus is the lifecycle state of a parking lot
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum LotStatus {
+    /// Users can enter and leave
+    Open,
+    /// No new entering, parked users can still leave
+    Closed,
+    /// Temporarily unavailable, parked users can still leave
+    Maintenance,
+}
+
+impl Default for LotStatus {
+    fn default() -> Self {
+        LotStatus::Open
+    }
+}
+
+/// SpotCategory is the type of a parking position
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum SpotCategory {
+    /// The positions not declared in any other category, priced with the parking lot's price range
+    Standard,
+    EvCharging,
+    Accessible,
+    Compact,
+    Motorcycle,
+}
+
+impl Default for SpotCategory {
+    fn default() -> Self {
+        SpotCategory::Standard
+    }
+}
+
+/// SpotPool is the positions of one category in a parking lot with their own price range
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct SpotPool<Balance> {
+    pub capacity: u32,
+    pub remain: u32,
+    pub current_price: Balance,
+    pub min_price: Balance,
+    pub max_price: Balance,
+}
+
+impl<Balance: Copy> SpotPool<Balance> {
+    /// Compute the price of the pool for its current occupancy with the price curve of the parking lot
+    pub fn compute_price<P: PricingModel<Balance>>(
+        &self,
+        pricing: &PriceCurve<Balance>,
+    ) -> result::Result<Balance, &'static str> {
+        let occupied = self
+            .capacity
+            .checked_sub(self.remain)
+            .ok_or("Remained num greater than capacity")?;
+        P::price(pricing, self.min_price, self.max_price, self.capacity, occupied)
+    }
+}
+
 /// ParkingLot store parking lot's info
 #[cfg_attr(feature = "std", derive(Debug))]
 #[derive(Encode, Decode, Clone, PartialEq, Eq)]
@@ -33,15 +235,18 @@ pub struct ParkingLot<T: Trait> {
     pub current_price: BalanceOf<T>,
     pub min_price: BalanceOf<T>,
     pub max_price: BalanceOf<T>,
-    pub latitude: i32,
-    pub longitude: i32,
+    pub location: GeoPoint,
+    pub status: LotStatus,
+    pub pricing: PriceCurve<BalanceOf<T>>,
+    /// Positions of the categories other than `Standard`, they are included in `capacity` and `remain`
+    /// while the price fields above are for the standard positions
+    pub categories: Vec<(SpotCategory, SpotPool<BalanceOf<T>>)>,
 }
 
 impl<T: Trait> ParkingLot<T> {
     pub fn new(
         owner: T::AccountId,
-        latitude: i32,
-        longitude: i32,
+        location: GeoPoint,
         capacity: u32,
         min_price: BalanceOf<T>,
         max_price: BalanceOf<T>,
@@ -53,56 +258,97 @@ impl<T: Trait> ParkingLot<T> {
             min_price,
             max_price,
             remain: capacity,
-            latitude,
-            longitude,
+            location,
+            status: LotStatus::Open,
+            pricing: PriceCurve::Linear,
+            categories: Vec::new(),
+        }
+    }
+
+    /// Number of the standard positions
+    pub fn standard_capacity(&self) -> u32 {
+        let categorized: u32 = self.categories.iter().map(|(_, pool)| pool.capacity).sum();
+        self.capacity.saturating_sub(categorized)
+    }
+
+    /// Number of the free standard positions
+    pub fn standard_remain(&self) -> u32 {
+        let categorized: u32 = self.categories.iter().map(|(_, pool)| pool.remain).sum();
+        self.remain.saturating_sub(categorized)
+    }
+
+    /// The positions of a category other than `Standard`
+    pub fn pool(&self, category: SpotCategory) -> Option<&SpotPool<BalanceOf<T>>> {
+        self.categories
+            .iter()
+            .find(|(pool_category, _)| *pool_category == category)
+            .map(|(_, pool)| pool)
+    }
+
+    /// Current price per second of a position of the category
+    pub fn category_price(&self, category: SpotCategory) -> result::Result<BalanceOf<T>, &'static str> {
+        match category {
+            SpotCategory::Standard => Ok(self.current_price),
+            category => Ok(self.pool(category).ok_or("The parking lot has no spots of the category")?.current_price),
+        }
+    }
+
+    fn pool_mut(&mut self, category: SpotCategory) -> Option<&mut SpotPool<BalanceOf<T>>> {
+        self.categories
+            .iter_mut()
+            .find(|(pool_category, _)| *pool_category == category)
+            .map(|(_, pool)| pool)
+    }
+
+    /// Take a position of the category and update its price
+    fn occupy(&mut self, category: SpotCategory) -> Result {
+        self.remain = self.remain.checked_sub(1).ok_or("The parking lot has no more position")?;
+        if let Some(pool) = self.pool_mut(category) {
+            pool.remain = pool.remain.checked_sub(1).ok_or("The parking lot has no more position")?;
+        }
+        self.update_prices()
+    }
+
+    /// Free a position of the category and update its price
+    fn release(&mut self, category: SpotCategory) -> Result {
+        self.remain += 1;
+        if let Some(pool) = self.pool_mut(category) {
+            pool.remain += 1;
+        }
+        self.update_prices()
+    }
+
+    /// Recompute the current prices of the standard positions and every category
+    fn update_prices(&mut self) -> Result {
+        self.current_price = self.compute_current_price()?;
+        let pricing = self.pricing.clone();
+        for (_, pool) in self.categories.iter_mut() {
+            pool.current_price = pool.compute_price::<T::PricingModel>(&pricing)?;
         }
+        Ok(())
     }
 
+    /// Compute the fee of a standard position from `old_time` to `new_time` at the price of the current occupancy
+    /// adjusted by the weekly schedule, returns the fee in thousandths of the balance and the price
     pub fn compute_new_fee(
         &self,
         new_time: T::Moment,
         old_time: T::Moment,
-    ) -> result::Result<(BalanceOf<T>, BalanceOf<T>), &'static str> {
-        let capacity = self.capacity as u64;
-        let remain = self.remain as u64;
-        let current_num = capacity
-            .checked_sub(remain)
-            .ok_or("Remained num greater than capacity")?;
-        let diff_time = new_time
-            .checked_sub(&old_time)
-            .ok_or("current time must greater than exiting time")?
-            .checked_div(&to_moment::<T>(1000)?)
-            .ok_or("Div diff time overflow")?;
-
-        let diff_time = TryInto::<u64>::try_into(diff_time).map_err(|_| "Time diff overflow")?;
-        let diff_price = self
-            .max_price
-            .checked_sub(&self.min_price)
-            .ok_or("Max price must be greater than min price")?;
-
-        let diff_price = TryInto::<u64>::try_into(diff_price).map_err(|_| "Price diff overflow")?;
-        let min_price = TryInto::<u64>::try_into(self.min_price).map_err(|_| "Min price overflow")?;
-
-        let current_price = current_num
-            .checked_mul(diff_price)
-            .ok_or("Mul overflow")?
-            .checked_div(capacity)
-            .ok_or("Div overflow")?
-            .checked_add(min_price)
-            .ok_or("Add overflow")?;
-
-        let fee = diff_time * current_price;
-
-        log::info!(
-            "end compute new fee\n fee: {}, current_price: {}, diff time: {}s diff price: {}\n\n",
-            fee,
-            current_price,
-            diff_time,
-            diff_price
-        );
+        schedule: &[ScheduleEntry<BalanceOf<T>>],
+    ) -> result::Result<(u128, BalanceOf<T>), &'static str> {
+        ensure!(new_time >= old_time, "current time must greater than exiting time");
+        let current_price = self.compute_current_price()?;
+        let fee = scheduled_fee_millis::<T>(schedule, current_price, old_time, new_time)?;
+        Ok((fee, current_price))
+    }
 
-        let fee = fee.try_into().map_err(|_| "Fee overflow")?;
-        Ok((fee, current_price.try_into().map_err(|_| "Current price overflow")?))
+    /// Compute the price of the standard positions for their current occupancy without accruing any fee
+    pub fn compute_current_price(&self) -> result::Result<BalanceOf<T>, &'static str> {
+        let capacity = self.standard_capacity();
+        let occupied = capacity
+            .checked_sub(self.standard_remain())
+            .ok_or("Remained num greater than capacity")?;
+        T::PricingModel::price(&self.pricing, self.min_price, self.max_price, capacity, occupied)
     }
 }
 
@@ -114,6 +360,481 @@ fn to_moment<T: Trait>(val: u64) -> result::Result<T::Moment, &'static str> {
     val.try_into().map_err(|_| "Convert to Moment type overflow")
 }
 
+/// Fees are accrued in thousandths of the balance, i.e. a price per second times milliseconds,
+/// so that no part of a second is lost between two accruals
+pub const MILLIS_PER_SECOND: u128 = 1000;
+
+/// Fee in thousandths of the balance of staying from `from` to `to` at a fixed price per second
+fn fee_millis<T: Trait>(price: BalanceOf<T>, from: T::Moment, to: T::Moment) -> result::Result<u128, &'static str> {
+    let diff_time = to
+        .checked_sub(&from)
+        .ok_or("End time must not be earlier than start time")?;
+    let diff_time = TryInto::<u64>::try_into(diff_time).map_err(|_| "Time diff overflow")?;
+    let price = TryInto::<u128>::try_into(price).map_err(|_| "Price overflow")?;
+    price.checked_mul(u128::from(diff_time)).ok_or("Fee overflow")
+}
+
+/// SpotMap numbers the positions of a parking lot from 0, the bit of a spot in `occupied` is set while
+/// a user parks in it and the bit in `out_of_service` is set while the owner has taken it out of service
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq, Default)]
+pub struct SpotMap {
+    /// Number of the spots, including the ones out of service
+    pub count: u32,
+    pub occupied: Vec<u64>,
+    pub out_of_service: Vec<u64>,
+    /// The spots of every category other than `Standard`, the spots in none of them are standard
+    pub categories: Vec<(SpotCategory, Vec<u64>)>,
+}
+
+fn get_bit(bits: &[u64], spot: u32) -> bool {
+    bits.get((spot / 64) as usize).map_or(false, |word| (word >> (spot % 64)) & 1 == 1)
+}
+
+fn set_bit(bits: &mut Vec<u64>, spot: u32, value: bool) {
+    if let Some(word) = bits.get_mut((spot / 64) as usize) {
+        if value {
+            *word |= 1 << (spot % 64);
+        } else {
+            *word &= !(1 << (spot % 64));
+        }
+    }
+}
+
+impl SpotMap {
+    pub fn new(count: u32) -> Self {
+        let words = ((count + 63) / 64) as usize;
+        SpotMap {
+            count,
+            occupied: vec![0; words],
+            out_of_service: vec![0; words],
+            categories: Vec::new(),
+        }
+    }
+
+    pub fn is_occupied(&self, spot: u32) -> bool {
+        get_bit(&self.occupied, spot)
+    }
+
+    pub fn is_out_of_service(&self, spot: u32) -> bool {
+        get_bit(&self.out_of_service, spot)
+    }
+
+    /// Number of the spots out of service
+    pub fn out_of_service_count(&self) -> u32 {
+        self.out_of_service.iter().map(|word| word.count_ones()).sum()
+    }
+
+    /// The category of the spot
+    pub fn category(&self, spot: u32) -> SpotCategory {
+        self.categories
+            .iter()
+            .find(|(_, bits)| get_bit(bits, spot))
+            .map_or(SpotCategory::Standard, |(category, _)| *category)
+    }
+
+    /// Number of the spots of the category
+    pub fn category_count(&self, category: SpotCategory) -> u32 {
+        let count = |bits: &Vec<u64>| bits.iter().map(|word| word.count_ones()).sum::<u32>();
+        match category {
+            SpotCategory::Standard => self.count - self.categories.iter().map(|(_, bits)| count(bits)).sum::<u32>(),
+            category => self
+                .categories
+                .iter()
+                .find(|(spot_category, _)| *spot_category == category)
+                .map_or(0, |(_, bits)| count(bits)),
+        }
+    }
+
+    /// The bits of the spots of the category in a word
+    fn category_word(&self, category: SpotCategory, index: usize) -> u64 {
+        let word = |bits: &Vec<u64>| bits.get(index).cloned().unwrap_or(0);
+        match category {
+            SpotCategory::Standard => !self.categories.iter().fold(0, |others, (_, bits)| others | word(bits)),
+            category => self
+                .categories
+                .iter()
+                .find(|(spot_category, _)| *spot_category == category)
+                .map_or(0, |(_, bits)| word(bits)),
+        }
+    }
+
+    /// The lowest spot of the category which is neither occupied nor out of service
+    pub fn first_free(&self, category: SpotCategory) -> Option<u32> {
+        (0..self.occupied.len())
+            .map(|index| {
+                let taken = self.occupied[index] | self.out_of_service[index];
+                (index, self.category_word(category, index) & !taken)
+            })
+            .find(|(_, free)| *free != 0)
+            .map(|(index, free)| index as u32 * 64 + free.trailing_zeros())
+            .filter(|spot| *spot < self.count)
+    }
+
+    fn set_occupied(&mut self, spot: u32, occupied: bool) {
+        set_bit(&mut self.occupied, spot, occupied);
+    }
+
+    fn set_out_of_service(&mut self, spot: u32, out_of_service: bool) {
+        set_bit(&mut self.out_of_service, spot, out_of_service);
+    }
+
+    fn set_category(&mut self, spot: u32, category: SpotCategory) {
+        let words = self.occupied.len();
+        for (_, bits) in self.categories.iter_mut() {
+            set_bit(bits, spot, false);
+        }
+        if category != SpotCategory::Standard {
+            match self.categories.iter_mut().find(|(spot_category, _)| *spot_category == category) {
+                Some((_, bits)) => set_bit(bits, spot, true),
+                None => {
+                    let mut bits = vec![0; words];
+                    set_bit(&mut bits, spot, true);
+                    self.categories.push((category, bits));
+                }
+            }
+        }
+        self.categories.retain(|(_, bits)| bits.iter().any(|word| *word != 0));
+    }
+
+    /// Give the category `count` spots, the spots are taken from the lowest free standard spots
+    /// or given back from the lowest free spots of the category
+    fn assign_category(&mut self, category: SpotCategory, count: u32) -> Result {
+        while self.category_count(category) < count {
+            let spot = self
+                .first_free(SpotCategory::Standard)
+                .ok_or("Not enough free spots for the category")?;
+            self.set_category(spot, category);
+        }
+        while self.category_count(category) > count {
+            let spot = self.first_free(category).ok_or("Not enough free spots for the category")?;
+            self.set_category(spot, SpotCategory::Standard);
+        }
+        Ok(())
+    }
+
+    /// Change the number of the spots, the removed spots must be free, in service and standard
+    fn resize(&mut self, count: u32) -> Result {
+        ensure!(
+            (count..self.count).all(|spot| {
+                !self.is_occupied(spot)
+                    && !self.is_out_of_service(spot)
+                    && self.category(spot) == SpotCategory::Standard
+            }),
+            "Spots to remove are still in use"
+        );
+        let words = ((count + 63) / 64) as usize;
+        self.occupied.resize(words, 0);
+        self.out_of_service.resize(words, 0);
+        for (_, bits) in self.categories.iter_mut() {
+            bits.resize(words, 0);
+        }
+        self.count = count;
+        Ok(())
+    }
+}
+
+/// Minutes of a week, the schedules of parking lots repeat every week
+pub const MINUTES_PER_WEEK: u16 = 10_080;
+
+/// The most entries of the schedule of a parking lot
+pub const MAX_SCHEDULE_ENTRIES: usize = 48;
+
+const MILLIS_PER_MINUTE: u64 = 60_000;
+
+const MILLIS_PER_WEEK: u64 = MINUTES_PER_WEEK as u64 * MILLIS_PER_MINUTE;
+
+/// The unix epoch is a Thursday, the weeks of the schedules start on Monday 00:00 UTC three days later
+const EPOCH_WEEK_OFFSET: u64 = 3 * 24 * 60 * MILLIS_PER_MINUTE;
+
+/// PriceRule changes the price of a parking lot during a range of the week
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub enum PriceRule<Balance> {
+    /// Percent of the price for the occupancy, e.g. 150 at peak hours or 50 at night
+    Percent(u32),
+    /// A price per second whatever the occupancy is
+    Fixed(Balance),
+}
+
+impl<Balance: SimpleArithmetic + Copy> PriceRule<Balance> {
+    /// The price per second under the rule
+    pub fn apply(&self, price: Balance) -> result::Result<Balance, &'static str> {
+        match self {
+            PriceRule::Percent(percent) => {
+                let price = price.checked_mul(&Balance::from(*percent)).ok_or("Scheduled price overflow")?;
+                Ok(price / Balance::from(100u32))
+            }
+            PriceRule::Fixed(fixed) => Ok(*fixed),
+        }
+    }
+}
+
+/// ScheduleEntry applies a price rule from `start` until right before `end`, they are minutes since Monday 00:00 UTC
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct ScheduleEntry<Balance> {
+    pub start: u16,
+    pub end: u16,
+    pub rule: PriceRule<Balance>,
+}
+
+/// Check that the entries are in order, do not overlap and are within the week
+fn validate_schedule<Balance>(schedule: &[ScheduleEntry<Balance>]) -> Result {
+    ensure!(schedule.len() <= MAX_SCHEDULE_ENTRIES, "Too many schedule entries");
+    let mut last_end = 0;
+    for entry in schedule {
+        ensure!(entry.start < entry.end, "Schedule entry must end after it starts");
+        ensure!(entry.end <= MINUTES_PER_WEEK, "Schedule entry must be within the week");
+        ensure!(entry.start >= last_end, "Schedule entries must be sorted and not overlap");
+        last_end = entry.end;
+    }
+    Ok(())
+}
+
+/// The rule at `offset` milliseconds of the week and the offset where it ends
+fn schedule_segment<Balance>(
+    schedule: &[ScheduleEntry<Balance>],
+    offset: u64,
+) -> (Option<&PriceRule<Balance>>, u64) {
+    for entry in schedule {
+        let start = u64::from(entry.start) * MILLIS_PER_MINUTE;
+        let end = u64::from(entry.end) * MILLIS_PER_MINUTE;
+        if offset < start {
+            return (None, start);
+        }
+        if offset < end {
+            return (Some(&entry.rule), end);
+        }
+    }
+    (None, MILLIS_PER_WEEK)
+}
+
+/// Fee of staying from `from` to `to` at a price per second adjusted by the weekly schedule, rounded down
+/// to the balance
+fn scheduled_fee<T: Trait>(
+    schedule: &[ScheduleEntry<BalanceOf<T>>],
+    price: BalanceOf<T>,
+    from: T::Moment,
+    to: T::Moment,
+) -> result::Result<BalanceOf<T>, &'static str> {
+    to_balance::<T>(scheduled_fee_millis::<T>(schedule, price, from, to)? / MILLIS_PER_SECOND)
+}
+
+/// Fee in thousandths of the balance of staying from `from` to `to` at a price per second adjusted
+/// by the weekly schedule, the stay is split at every boundary of the schedule
+fn scheduled_fee_millis<T: Trait>(
+    schedule: &[ScheduleEntry<BalanceOf<T>>],
+    price: BalanceOf<T>,
+    from: T::Moment,
+    to: T::Moment,
+) -> result::Result<u128, &'static str> {
+    if schedule.is_empty() {
+        return fee_millis::<T>(price, from, to);
+    }
+
+    let from = TryInto::<u64>::try_into(from).map_err(|_| "Time overflow")?;
+    let to = TryInto::<u64>::try_into(to).map_err(|_| "Time overflow")?;
+    ensure!(to >= from, "End time must not be earlier than start time");
+    let mut fee = 0u128;
+    let mut start = from;
+    while start < to {
+        let offset = (start + EPOCH_WEEK_OFFSET) % MILLIS_PER_WEEK;
+        let (rule, boundary) = schedule_segment(schedule, offset);
+        let end = cmp::min(to, start + (boundary - offset));
+        let segment_price = match rule {
+            Some(rule) => rule.apply(price)?,
+            None => price,
+        };
+        let segment_fee = fee_millis::<T>(segment_price, to_moment::<T>(start)?, to_moment::<T>(end)?)?;
+        fee = fee.checked_add(segment_fee).ok_or("Fee overflow")?;
+        start = end;
+    }
+    Ok(fee)
+}
+
+/// Price per second at `now` adjusted by the weekly schedule
+fn scheduled_price<T: Trait>(
+    schedule: &[ScheduleEntry<BalanceOf<T>>],
+    price: BalanceOf<T>,
+    now: T::Moment,
+) -> result::Result<BalanceOf<T>, &'static str> {
+    let now = TryInto::<u64>::try_into(now).map_err(|_| "Time overflow")?;
+    let offset = (now + EPOCH_WEEK_OFFSET) % MILLIS_PER_WEEK;
+    match schedule_segment(schedule, offset) {
+        (Some(rule), _) => rule.apply(price),
+        (None, _) => Ok(price),
+    }
+}
+
+/// The most minutes prepaid by one call
+pub const MAX_PREPAID_MINUTES: u32 = 24 * 60;
+
+/// End of `minutes` prepaid from `start`
+fn prepaid_end<T: Trait>(start: T::Moment, minutes: u32) -> result::Result<T::Moment, &'static str> {
+    ensure!(minutes > 0 && minutes <= MAX_PREPAID_MINUTES, "Prepaid minutes are out of range");
+    start
+        .checked_add(&to_moment::<T>(u64::from(minutes) * MILLIS_PER_MINUTE)?)
+        .ok_or("Time overflow")
+}
+
+/// Milliseconds of a bucket of the timer queues, i.e. the reservation deadlines, the booking ends, the pass
+/// expiries and the prepaid ends. A queue stores its timers by bucket so that a timer touches only its bucket
+pub const TIMER_BUCKET: u64 = MILLIS_PER_MINUTE;
+
+/// The most buckets of a timer queue walked in a block, a queue behind the time catches up in the next blocks
+pub const MAX_TIMER_BUCKETS: u64 = 64;
+
+/// Bucket of the timers at `time`
+fn timer_bucket<T: Trait>(time: T::Moment) -> u64 {
+    TryInto::<u64>::try_into(time).unwrap_or_else(|_| u64::max_value()) / TIMER_BUCKET
+}
+
+/// The first and the last bucket of a timer queue to walk at `now` from its cursor, and the cursor after walking
+/// them. The current bucket is walked again by the next blocks since it can still get timers which are not due
+fn due_buckets<T: Trait>(cursor: Option<u64>, now: T::Moment) -> Option<(u64, u64, u64)> {
+    let first = cursor?;
+    let current = timer_bucket::<T>(now);
+    if current < first {
+        return None;
+    }
+    let last = cmp::min(current, first.saturating_add(MAX_TIMER_BUCKETS - 1));
+    Some((first, last, if last == current { current } else { last + 1 }))
+}
+
+/// Milliseconds of the window of the daily fee cap
+pub const DAILY_CAP_WINDOW: u64 = 24 * 60 * MILLIS_PER_MINUTE;
+
+/// FeeRules limit the fee of a session in a parking lot
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FeeRules<Moment, Balance> {
+    /// Leaving within this time after entering is free, zero means no grace period
+    pub grace_period: Moment,
+    /// The most fee of one session
+    pub session_cap: Option<Balance>,
+    /// The most fee of the sessions in the parking lot during any 24 hours, a session longer than that
+    /// is capped for each 24 hours it has started
+    pub daily_cap: Option<Balance>,
+}
+
+/// FeeLimit is the fee rule which limited the fee of a session
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum FeeLimit {
+    /// The fee is not limited
+    Unlimited,
+    /// The user left within the grace period
+    GracePeriod,
+    /// The fee is the session cap
+    SessionCap,
+    /// The fee is what is left of the daily cap
+    DailyCap,
+}
+
+impl Default for FeeLimit {
+    fn default() -> Self {
+        FeeLimit::Unlimited
+    }
+}
+
+/// Granularity is the unit of the billed parking time
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    Second,
+    Minute,
+    QuarterHour,
+}
+
+impl Granularity {
+    /// Milliseconds of the unit
+    pub fn millis(&self) -> u64 {
+        match self {
+            Granularity::Second => 1000,
+            Granularity::Minute => MILLIS_PER_MINUTE,
+            Granularity::QuarterHour => 15 * MILLIS_PER_MINUTE,
+        }
+    }
+}
+
+/// Rounding is how a parking time is rounded to whole units
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+    /// Half a unit or more is rounded up
+    Nearest,
+}
+
+/// Billing decides how the parking time of a session is billed, the fee accrued to the millisecond
+/// is scaled to the billed time
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub struct Billing {
+    pub granularity: Granularity,
+    pub rounding: Rounding,
+}
+
+impl Default for Billing {
+    fn default() -> Self {
+        Billing {
+            granularity: Granularity::Second,
+            rounding: Rounding::Down,
+        }
+    }
+}
+
+impl Billing {
+    /// The billed milliseconds of a parking time
+    pub fn billed_millis(&self, millis: u64) -> u64 {
+        let unit = self.granularity.millis();
+        let units = match self.rounding {
+            Rounding::Down => millis / unit,
+            Rounding::Up => millis / unit + if millis % unit > 0 { 1 } else { 0 },
+            Rounding::Nearest => millis / unit + if millis % unit >= unit / 2 { 1 } else { 0 },
+        };
+        units * unit
+    }
+}
+
+/// The most sessions watched for exceeding the price bounds of their users
+pub const MAX_GUARDED_SESSIONS: usize = 256;
+
+/// GuardAction is what happens when the price or the fee of a session exceeds the bounds of the user
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum GuardAction {
+    /// The bounds are only checked at entering
+    Ignore,
+    /// Deposit an event once
+    Alert,
+    /// Leave the parking lot and pay the fee so far
+    Leave,
+}
+
+/// PriceGuard is the price and the fee a user accepts for a session
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub struct PriceGuard<Balance> {
+    /// The highest price per second, a booked session pays the booked price after its window
+    pub max_price_per_second: Balance,
+    /// The highest fee of the session
+    pub max_total: Option<Balance>,
+    pub action: GuardAction,
+}
+
+impl<Balance: Bounded> Default for PriceGuard<Balance> {
+    fn default() -> Self {
+        PriceGuard {
+            max_price_per_second: Balance::max_value(),
+            max_total: None,
+            action: GuardAction::Ignore,
+        }
+    }
+}
+
 /// ParkingInfo stores parking info of user
 #[cfg_attr(feature = "std", derive(Debug))]
 #[derive(Encode, Decode, Clone, PartialEq, Eq)]
@@ -124,6 +845,38 @@ pub struct ParkingInfo<T: Trait> {
     pub enter_time: T::Moment,
     pub current_time: T::Moment,
     pub current_fee: BalanceOf<T>,
+    /// Part of `current_fee` already paid during the parking, e.g. to a previous owner
+    pub paid_fee: BalanceOf<T>,
+    /// Fee index of the category in the parking lot when `current_fee` was computed
+    pub fee_index: u128,
+    /// Fee charged at entering, e.g. the booked window, the metered fee is billed on top of it
+    pub base_fee: BalanceOf<T>,
+    /// Fee accrued since entering in thousandths of the balance before billing
+    pub metered_fee: u128,
+    /// Balance reserved from the user as the parking deposit
+    pub deposit: BalanceOf<T>,
+    /// End and price of the booked window the user entered with, the booked window is paid at entering
+    /// and the stay after it is charged at the booked price
+    pub booking: Option<(T::Moment, BalanceOf<T>)>,
+    /// Category of the position the user parks in
+    pub category: SpotCategory,
+    /// Spot assigned to the user if the spots of the parking lot are numbered
+    pub spot: Option<u32>,
+    /// Price and fee bounds the user entered with
+    pub price_guard: PriceGuard<BalanceOf<T>>,
+    /// Price per second frozen at entering a parking lot with locked rates, charged for the whole stay
+    /// instead of following the occupancy and the price schedule
+    pub locked_price: Option<BalanceOf<T>>,
+    /// End of the time a prepaid session has paid up front
+    pub paid_until: Option<T::Moment>,
+    /// Price per second charged for the stay after the prepaid time
+    pub overstay_rate: BalanceOf<T>,
+    /// Whether the user entered with a pass of the parking lot, the stay is free until the pass expires
+    pub pass: bool,
+    /// When the pass expired during the stay, only the stay after it is metered
+    pub pass_ended: Option<T::Moment>,
+    /// Hash of the promo code and the discount of the coupon applied to the session
+    pub coupon: Option<(T::Hash, Discount<BalanceOf<T>>)>,
 }
 
 impl<T: Trait> ParkingInfo<T> {
@@ -135,25 +888,234 @@ impl<T: Trait> ParkingInfo<T> {
             enter_time,
             current_time: enter_time.clone(),
             current_fee: 0.into(),
+            paid_fee: 0.into(),
+            fee_index: 0,
+            base_fee: Zero::zero(),
+            metered_fee: 0,
+            deposit: Zero::zero(),
+            booking: None,
+            category: SpotCategory::Standard,
+            spot: None,
+            price_guard: Default::default(),
+            locked_price: None,
+            paid_until: None,
+            overstay_rate: Zero::zero(),
+            pass: false,
+            pass_ended: None,
+            coupon: None,
+        }
+    }
+}
+
+/// Reservation holds a position of a parking lot for a user until the arriving deadline
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct Reservation<T: Trait> {
+    pub parking_lot_hash: T::Hash,
+    pub arrive_by: T::Moment,
+    pub deposit: BalanceOf<T>,
+}
+
+/// The most windows booked by one call
+pub const MAX_BOOKING_WINDOWS: usize = 32;
+
+/// The largest radius in meters of searching parking lots near a point
+pub const MAX_NEAR_RADIUS: u32 = 50_000;
+
+/// The most cells of the location index read by searching parking lots near a point, a search of
+/// `MAX_NEAR_RADIUS` overlaps less than 100 cells at any latitude
+pub const MAX_NEAR_CELLS: usize = 128;
+
+/// Booking holds a position of a parking lot for a user during a future time window
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct Booking<T: Trait> {
+    pub user: T::AccountId,
+    pub parking_lot_hash: T::Hash,
+    /// The window starts at `start` and ends right before `end`
+    pub start: T::Moment,
+    pub end: T::Moment,
+    /// Price per second of the parking lot when booking
+    pub price: BalanceOf<T>,
+    /// Fee of the whole window at the booked price, reserved from the user until entering
+    pub amount: BalanceOf<T>,
+    /// Whether a position is held for the window, it is held when the window starts
+    pub held: bool,
+}
+
+/// BookingRules decide how much of the booked amount is refunded when a user cancels a booking
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BookingRules<Moment> {
+    /// Cancelling at least this long before the window starts refunds the whole amount
+    pub free_cancel_notice: Moment,
+    /// Percent of the amount refunded when cancelling later, the rest goes to the owner
+    pub late_refund_percent: u8,
+}
+
+/// The most pass products of a parking lot
+pub const MAX_PASS_PRODUCTS: usize = 16;
+
+/// PassProduct is a pass of a parking lot sold by its owner, a pass holds a standard position until it expires
+/// and its holder parks free of charge
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct PassProduct<Moment, Balance> {
+    pub price: Balance,
+    /// How long a pass is valid after buying or renewing it
+    pub duration: Moment,
+    /// The most passes valid at the same time, zero stops selling and renewing the product
+    pub limit: u32,
+    /// Number of the passes valid now
+    pub sold: u32,
+}
+
+/// Pass of a user for a parking lot
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct Pass<Moment> {
+    /// Index of the product in the pass products of the parking lot
+    pub product: u32,
+    pub expires: Moment,
+    /// Buy the product again when the pass expires
+    pub auto_renew: bool,
+}
+
+/// The longest promo code of a coupon
+pub const MAX_COUPON_CODE_LENGTH: usize = 64;
+
+/// Discount is how a coupon reduces the fee of a session
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq)]
+pub enum Discount<Balance> {
+    /// Percent of the fee taken off
+    Percent(u8),
+    /// Amount taken off the fee
+    Fixed(Balance),
+}
+
+impl<Balance: SimpleArithmetic + Copy> Discount<Balance> {
+    /// The part of the fee taken off, not more than the fee
+    pub fn amount(&self, fee: Balance) -> Balance {
+        match self {
+            Discount::Percent(percent) => Permill::from_percent((*percent).into()) * fee,
+            Discount::Fixed(amount) => cmp::min(*amount, fee),
         }
     }
 }
 
+/// Coupon is issued by an owner for one of their parking lots or all of them, it is stored by the hash of its
+/// promo code
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Encode, Decode, Clone, PartialEq, Eq)]
+pub struct Coupon<T: Trait> {
+    pub owner: T::AccountId,
+    /// The parking lot the coupon is for, all parking lots of the owner if nothing
+    pub parking_lot_hash: Option<T::Hash>,
+    pub discount: Discount<BalanceOf<T>>,
+    /// Number of the sessions the coupon can still be applied to
+    pub uses_left: u32,
+    /// The coupon cannot be applied from this time
+    pub expires: T::Moment,
+}
+
 decl_event!(
     pub enum Event<T>
     where
         AccountId = <T as system::Trait>::AccountId,
+        Hash = <T as system::Trait>::Hash,
         Moment = <T as timestamp::Trait>::Moment,
+        Balance = BalanceOf<T>,
         ParkingLotInfo = ParkingLot<T>,
         EnteringInfo = ParkingInfo<T>,
         LeavingInfo = ParkingInfo<T>,
+        Pricing = PriceCurve<BalanceOf<T>>,
+        BookingInfo = Booking<T>,
+        Rules = BookingRules<<T as timestamp::Trait>::Moment>,
+        Pool = SpotPool<BalanceOf<T>>,
+        Schedule = Vec<ScheduleEntry<BalanceOf<T>>>,
+        LotFeeRules = FeeRules<<T as timestamp::Trait>::Moment, BalanceOf<T>>,
+        Product = PassProduct<<T as timestamp::Trait>::Moment, BalanceOf<T>>,
+        CouponInfo = Coupon<T>,
     {
         /// Deposit a new parking lot
         NewParkingLot(Moment, ParkingLotInfo),
         /// Deposit a event that current user enter the parking lot
         Entering(Moment, EnteringInfo),
-        /// Deposit a event that current user leave the parkint lot
-        Leaving(Moment, AccountId, AccountId, LeavingInfo),
+        /// Deposit a event that current user leave the parkint lot, with the fee rule that limited the fee
+        Leaving(Moment, AccountId, AccountId, LeavingInfo, FeeLimit),
+        /// Deposit a event that owner updated prices, capacity or location of the parking lot
+        ParkingLotUpdated(Moment, Hash, ParkingLotInfo),
+        /// Deposit a event that owner changed the status of the parking lot
+        ParkingLotStatusChanged(Moment, Hash, LotStatus),
+        /// Deposit a event that owner removed an empty parking lot
+        ParkingLotRemoved(Moment, AccountId, Hash),
+        /// Deposit a event that the parking lot is transferred from the old owner to the new owner
+        ParkingLotTransferred(Moment, Hash, AccountId, AccountId),
+        /// Deposit a event that owner changed the price curve of the parking lot
+        PricingChanged(Moment, Hash, Pricing),
+        /// Deposit a event that user cannot pay the whole fee and owes the balance to the owner
+        DebtCreated(Moment, AccountId, AccountId, Balance),
+        /// Deposit a event that user paid the debt to the owner
+        DebtSettled(Moment, AccountId, AccountId, Balance),
+        /// Deposit a event that user reserved a position of the parking lot until the deadline
+        SpotReserved(Moment, AccountId, Hash, Moment),
+        /// Deposit a event that user did not arrive in time and the deposit is forfeited to the owner
+        ReservationExpired(Moment, AccountId, Hash, Balance),
+        /// Deposit a event that user booked a time window of the parking lot
+        Booked(Moment, Hash, BookingInfo),
+        /// Deposit a event that user cancelled the booking and got the balance refunded
+        BookingCancelled(Moment, Hash, Balance),
+        /// Deposit a event that user did not come during the booked window and the amount is forfeited to the owner
+        BookingForfeited(Moment, Hash, Balance),
+        /// Deposit a event that no position was free to hold for the booked window and the amount is refunded
+        BookingRefunded(Moment, Hash, Balance),
+        /// Deposit a event that owner changed the booking rules of the parking lot
+        BookingRulesChanged(Moment, Hash, Rules),
+        /// Deposit a event that owner changed the positions of a category of the parking lot
+        SpotCategoryChanged(Moment, Hash, SpotCategory, Pool),
+        /// Deposit a event that owner started or stopped numbering the spots of the parking lot
+        SpotNumberingChanged(Moment, Hash, bool),
+        /// Deposit a event that owner put a spot of the parking lot back into service or took it out of service
+        SpotServiceChanged(Moment, Hash, u32, bool),
+        /// Deposit a event that owner changed the weekly price schedule of the parking lot
+        PriceScheduleChanged(Moment, Hash, Schedule),
+        /// Deposit a event that owner changed the fee rules of the parking lot
+        FeeRulesChanged(Moment, Hash, LotFeeRules),
+        /// Deposit a event that owner changed how the parking time of the parking lot is billed
+        BillingChanged(Moment, Hash, Billing),
+        /// Deposit a event that owner enabled or disabled locked rates of the parking lot
+        LockedRateChanged(Moment, Hash, bool),
+        /// Deposit a event that owner changed the overstay rate of the prepaid sessions of the parking lot
+        OverstayRateChanged(Moment, Hash, Option<Balance>),
+        /// Deposit a event that user extended the prepaid session until the time, with the fee paid
+        PrepaidExtended(Moment, AccountId, Moment, Balance),
+        /// Deposit a event that the prepaid time of the user's session in the parking lot has run out
+        Expired(Moment, AccountId, Hash),
+        /// Deposit a event that owner added or changed a pass product of the parking lot
+        PassProductChanged(Moment, Hash, u32, Product),
+        /// Deposit a event that user bought a pass product of the parking lot valid until the time
+        PassBought(Moment, AccountId, Hash, u32, Moment),
+        /// Deposit a event that user turned the auto-renewal of the pass of the parking lot on or off
+        PassRenewalChanged(Moment, AccountId, Hash, bool),
+        /// Deposit a event that the pass of the user for the parking lot was renewed until the time
+        PassRenewed(Moment, AccountId, Hash, Moment),
+        /// Deposit a event that the pass of the user for the parking lot expired
+        PassExpired(Moment, AccountId, Hash),
+        /// Deposit a event that owner issued a coupon with the hash of its promo code
+        CouponIssued(Moment, Hash, CouponInfo),
+        /// Deposit a event that owner revoked the coupon with the hash of its promo code
+        CouponRevoked(Moment, Hash),
+        /// Deposit a event that user paid the session with the discount of the coupon
+        CouponRedeemed(Moment, AccountId, Hash, Balance),
+        /// Deposit a event that the sudo key set or cleared the commission of the parking lot, or of all parking lots
+        /// without a parking lot
+        CommissionChanged(Moment, Option<Hash>, Option<Permill>),
+        /// Deposit a event that the sudo key paid the balance from the treasury to the account
+        TreasurySpent(Moment, AccountId, Balance),
+        /// Deposit a event that the price or the fee of the user's session in the parking lot exceeded the bounds
+        /// of the user, with the price and the fee at that time
+        PriceGuardTriggered(Moment, AccountId, Hash, Balance, Balance, GuardAction),
     }
 );
 
@@ -163,21 +1125,103 @@ decl_storage! {
         OwnerParkingLotsCount get(owner_parking_lots_count): map T::AccountId => u64;
         /// Access the all parking lot infos
         OwnerParkingLotsArray get(owner_parking_lots_array): map (T::AccountId, u64) => T::Hash;
+        /// Position of a parking lot in `OwnerParkingLotsArray` of its owner
+        OwnerParkingLotIndex get(owner_parking_lot_index): map T::Hash => u64;
         /// index to one parking lot hash
         ParkingLotsByIndex get(parking_lots_by_index): map u64 => T::Hash;
+        /// Position of a parking lot in `ParkingLotsByIndex`
+        ParkingLotIndex get(parking_lot_index): map T::Hash => u64;
+        /// Numbered spots of a parking lot, the parking lots without it only count their positions
+        ParkingLotSpots get(parking_lot_spots): map T::Hash => Option<SpotMap>;
+        /// Parking lots in a cell of the location grid, see `GeoPoint::cell`
+        LotsByCell get(lots_by_cell): map (i32, i32) => Vec<T::Hash>;
         /// Hash map to one parking lot
         ParkingLots get(parking_lots): map T::Hash => Option<ParkingLot<T>>;
         /// Last time for the parking lot fresh fees
         ParkingLotLastTime get(parking_lot_last_time): map T::Hash => Option<T::Moment>;
-        /// All user id of current parking lot
-        CurrentParkingAccounts get(current_parking_accounts): map T::Hash => Vec<T::AccountId>;
+        /// Fee of one position in thousandths of the balance accumulated since the parking lot was created,
+        /// a user pays the increase of it between entering and leaving
+        ParkingLotFeeIndex get(parking_lot_fee_index): map T::Hash => u128;
+        /// Fee index of the positions of a category other than `Standard`, see `ParkingLotFeeIndex`
+        CategoryFeeIndex get(category_fee_index): map (T::Hash, SpotCategory) => u128;
+        /// Whether the users entering a parking lot keep the price at entering for the whole stay
+        ParkingLotLockedRate get(parking_lot_locked_rate): map T::Hash => bool;
+        /// Price per second charged after the prepaid time of a session, only parking lots with it take
+        /// prepaid sessions
+        ParkingLotOverstayRate get(parking_lot_overstay_rate): map T::Hash => Option<BalanceOf<T>>;
+        /// How the parking time of the sessions of a parking lot is billed
+        ParkingLotBilling get(parking_lot_billing): map T::Hash => Billing;
+        /// Number of the users currently parked in a parking lot
+        CurrentParkingCount get(current_parking_count): map T::Hash => u32;
+        /// Access the users currently parked in a parking lot by index
+        CurrentParkingAccounts get(current_parking_account): map (T::Hash, u32) => T::AccountId;
+        /// Position of a parked user in `CurrentParkingAccounts` of its parking lot
+        CurrentParkingIndex get(current_parking_index): map T::AccountId => u32;
         /// Total number of parking lots
         AllParkingLotsCount get(all_parking_lots_count): u64;
         /// Parking info of current user
         UserParkingInfo get(user_parking_info): map T::AccountId => Option<ParkingInfo<T>>;
+        /// Pass products of a parking lot, a product is never removed so that its index stays
+        PassProducts get(pass_products): map T::Hash => Vec<PassProduct<T::Moment, BalanceOf<T>>>;
+        /// Pass of a user for a parking lot
+        UserPasses get(user_passes): map (T::AccountId, T::Hash) => Option<Pass<T::Moment>>;
+        /// Expiry times of the passes by bucket of `TIMER_BUCKET`, sorted by the time
+        PassExpiries get(pass_expiries): map u64 => Vec<(T::Moment, T::AccountId, T::Hash)>;
+        /// First bucket of `PassExpiries` which can have passes, none before the first pass
+        PassExpiryCursor get(pass_expiry_cursor): Option<u64>;
+        /// Ends of the prepaid time of the prepaid sessions which have not run out by bucket of `TIMER_BUCKET`,
+        /// sorted by the end
+        PrepaidEnds get(prepaid_ends): map u64 => Vec<(T::Moment, T::AccountId)>;
+        /// First bucket of `PrepaidEnds` which can have prepaid ends, none before the first prepaid session
+        PrepaidEndCursor get(prepaid_end_cursor): Option<u64>;
+        /// Coupons by the hash of their promo codes
+        Coupons get(coupons): map T::Hash => Option<Coupon<T>>;
+        /// Hashes of the promo codes of the coupons issued for a parking lot, they are removed with it
+        ParkingLotCoupons get(parking_lot_coupons): map T::Hash => Vec<T::Hash>;
+        /// Commission of all parking lots set by the sudo key instead of `Trait::Commission`
+        PlatformCommission get(platform_commission): Option<Permill>;
+        /// Commission of a parking lot set by the sudo key instead of the commission of all parking lots
+        ParkingLotCommission get(parking_lot_commission): map T::Hash => Option<Permill>;
+        /// Users whose sessions are watched for exceeding their price bounds
+        GuardedSessions get(guarded_sessions): Vec<T::AccountId>;
+        /// Unpaid fees of a user and the owners they are owed to
+        UserDebts get(user_debts): map T::AccountId => Vec<(T::AccountId, BalanceOf<T>)>;
+        /// Position reserved by a user
+        Reservations get(reservations): map T::AccountId => Option<Reservation<T>>;
+        /// Arriving deadlines of the reservations by bucket of `TIMER_BUCKET`, sorted by the deadline
+        ReservationDeadlines get(reservation_deadlines): map u64 => Vec<(T::Moment, T::AccountId)>;
+        /// First bucket of `ReservationDeadlines` which can have deadlines, none before the first reservation
+        ReservationDeadlineCursor get(reservation_deadline_cursor): Option<u64>;
+        /// Booking by its id
+        Bookings get(bookings): map T::Hash => Option<Booking<T>>;
+        /// Windows and ids of the bookings of a parking lot
+        ParkingLotBookings get(parking_lot_bookings): map T::Hash => Vec<(T::Moment, T::Moment, T::Hash)>;
+        /// Ids of the bookings of a user
+        UserBookings get(user_bookings): map T::AccountId => Vec<T::Hash>;
+        /// Ends of the booked windows by bucket of `TIMER_BUCKET`, sorted by the end
+        BookingEnds get(booking_ends): map u64 => Vec<(T::Moment, T::Hash)>;
+        /// First bucket of `BookingEnds` which can have ends, none before the first booking
+        BookingEndCursor get(booking_end_cursor): Option<u64>;
+        /// Starts of the booked windows by bucket of `TIMER_BUCKET`, sorted by the start
+        BookingStarts get(booking_starts): map u64 => Vec<(T::Moment, T::Hash)>;
+        /// First bucket of `BookingStarts` which can have starts, none before the first booking
+        BookingStartCursor get(booking_start_cursor): Option<u64>;
+        /// Positions are held for the booked windows starting until this time
+        BookingsHeldUntil get(bookings_held_until): T::Moment;
+        /// Weekly price schedule of a parking lot, sorted by the start
+        ParkingLotSchedule get(parking_lot_schedule): map T::Hash => Vec<ScheduleEntry<BalanceOf<T>>>;
+        /// Grace period and fee caps of a parking lot
+        ParkingLotFeeRules get(parking_lot_fee_rules): map T::Hash => FeeRules<T::Moment, BalanceOf<T>>;
+        /// Leaving times and fees of the recent sessions of a user in a parking lot with a daily cap
+        UserDailyFees get(user_daily_fees):
+            double_map T::Hash, blake2_256(T::AccountId) => Vec<(T::Moment, BalanceOf<T>)>;
+        /// Refund rules for cancelling bookings of a parking lot
+        ParkingLotBookingRules get(parking_lot_booking_rules): map T::Hash => BookingRules<T::Moment>;
     }
 
     add_extra_genesis {
+        // (owner, remain, capacity, current price, min price, max price, latitude, longitude),
+        // coordinates are micro-degrees and must be in the range of `GeoPoint`
         config(parking_lots): Vec<(T::AccountId, u32, u32, BalanceOf<T>, BalanceOf<T>, BalanceOf<T>, i32, i32)>;
 
         build(|config: &GenesisConfig<T>| {
@@ -190,8 +1234,11 @@ decl_storage! {
                     current_price: parking_lot.3.clone(),
                     min_price: parking_lot.4.clone(),
                     max_price: parking_lot.5.clone(),
-                    latitude: parking_lot.6.clone(),
-                    longitude: parking_lot.7.clone(),
+                    location: GeoPoint::new(parking_lot.6, parking_lot.7)
+                        .expect("Location of genesis parking lot is out of range"),
+                    status: LotStatus::Open,
+                    pricing: PriceCurve::Linear,
+                    categories: Vec::new(),
                 };
                 <Module<T>>::_new_parking_lot(account, parking_lot).expect("Cannot be failed");
             }
@@ -206,299 +1253,4124 @@ decl_module! {
         // this is needed only if you are using events in your module
         fn deposit_event() = default;
 
+        /// The deposit reserved when entering
+        const EnteringDeposit: BalanceOf<T> = T::EnteringDeposit::get();
+
+        /// The deposit locked when reserving a position
+        const ReservationDeposit: BalanceOf<T> = T::ReservationDeposit::get();
+
+        /// The longest time a position can be reserved in advance
+        const MaxReservationPeriod: T::Moment = T::MaxReservationPeriod::get();
+
+        /// The default share of every payment to an owner taken for the treasury
+        const Commission: Permill = T::Commission::get();
+
+        /// Expire the reservations whose users did not arrive in time, the booked windows which have ended and
+        /// the passes which are not renewed
+        fn on_initialize(_n: T::BlockNumber) {
+            let now = <timestamp::Module<T>>::get();
+            Self::expire_reservations(now);
+            Self::expire_bookings(now);
+            Self::expire_passes(now);
+        }
+
+        /// Deposit events for the prepaid sessions which have run out, hold the positions of the booked windows
+        /// which have started and check the sessions whose users asked to be alerted or to leave beyond their
+        /// price bounds, at the time of this block
+        fn on_finalize(_n: T::BlockNumber) {
+            let now = <timestamp::Module<T>>::get();
+            Self::expire_prepaid(now);
+            Self::hold_bookings(now);
+            Self::check_price_guards(now);
+        }
+
         /// Create a new parking lot
         pub fn new_parking_lot(origin, latitude: i32, longitude: i32, capacity: u32, min_price: BalanceOf<T>, max_price: BalanceOf<T>) -> Result {
             let owner = ensure_signed(origin)?;
             ensure!(min_price <= max_price, "min price must equal and less than max price");
-            let parking = ParkingLot::<T>::new(owner.clone(), latitude, longitude, capacity, min_price, max_price);
+            let location = GeoPoint::new(latitude, longitude)?;
+            let parking = ParkingLot::<T>::new(owner.clone(), location, capacity, min_price, max_price);
 
             Self::_new_parking_lot(owner, parking.clone())?;
             Self::deposit_event(RawEvent::NewParkingLot(<timestamp::Module<T>>::get(), parking));
             Ok(())
         }
 
-        /// User entering by parking lot hash
-        pub fn entering(origin, parking_lot_hash: T::Hash) -> Result {
-            let user = ensure_signed(origin)?;
-            ensure!(!<UserParkingInfo<T>>::exists(user.clone()), "User already has entered a parking lot");
-
-            let info_hash = <system::Module<T>>::random_seed();
-            // record the entering time
+        /// Update prices, capacity and location of a parking lot, only the owner can do it
+        pub fn update_parking_lot(
+            origin,
+            parking_lot_hash: T::Hash,
+            latitude: i32,
+            longitude: i32,
+            capacity: u32,
+            min_price: BalanceOf<T>,
+            max_price: BalanceOf<T>
+        ) -> Result {
+            let owner = ensure_signed(origin)?;
+            ensure!(min_price <= max_price, "min price must equal and less than max price");
+            let location = GeoPoint::new(latitude, longitude)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can update the parking lot");
+            // reserved positions are kept as well as the parked users, the positions of the categories
+            // are unchanged so only the standard positions change
+            let parked = parking_lot.capacity - parking_lot.remain;
+            let typed_capacity = parking_lot.capacity - parking_lot.standard_capacity();
+            let standard_parked = parking_lot.standard_capacity() - parking_lot.standard_remain();
+            ensure!(
+                capacity >= typed_capacity + standard_parked,
+                "Capacity cannot be less than the number of parked users"
+            );
             let now = <timestamp::Module<T>>::get();
+            let bookings = Self::parking_lot_bookings(parking_lot_hash);
+            ensure!(
+                Self::peak_bookings(&bookings, now, T::Moment::max_value()) <= capacity - typed_capacity,
+                "Capacity cannot be less than the number of booked positions"
+            );
+            T::PricingModel::validate(&parking_lot.pricing, min_price, max_price)?;
+            // the spots out of service are not counted in the capacity
+            let spots = match Self::parking_lot_spots(parking_lot_hash) {
+                Some(mut spots) => {
+                    let count = capacity + spots.out_of_service_count();
+                    spots.resize(count)?;
+                    Some(spots)
+                }
+                None => None,
+            };
 
-            let parking_info = ParkingInfo::<T>::new(user.clone(), parking_lot_hash, info_hash, now);
-            let mut parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
-            if parking_lot.remain <= 0 {
-                return Err("The parking lot has no more position");
+            // settle the fees accrued under the old price curve before changing it
+            let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            if let Some(spots) = spots {
+                <ParkingLotSpots<T>>::insert(parking_lot_hash, spots);
             }
+            if parking_lot.location.cell() != location.cell() {
+                Self::remove_from_cell(parking_lot.location.cell(), parking_lot_hash);
+                <LotsByCell<T>>::mutate(location.cell(), |hashes| hashes.push(parking_lot_hash));
+            }
+            parking_lot.location = location;
+            parking_lot.capacity = capacity;
+            parking_lot.remain = capacity - parked;
+            parking_lot.min_price = min_price;
+            parking_lot.max_price = max_price;
+            parking_lot.update_prices()?;
 
-            let old_time = Self::parking_lot_last_time(parking_lot_hash).expect("It must be having data. Qed");
-            <ParkingLotLastTime<T>>::insert(parking_lot_hash, now);
+            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot.clone());
+            Self::deposit_event(RawEvent::ParkingLotUpdated(now, parking_lot_hash, parking_lot));
+            Ok(())
+        }
 
-            // no one in parking lot, we should init the time and no need to refresh fees
-            if parking_lot.remain != parking_lot.capacity {
-                Self::refresh_all_fee(&parking_lot, parking_lot_hash, now, old_time)?;
+        /// Change the price curve of a parking lot, only the owner can do it
+        pub fn set_pricing(origin, parking_lot_hash: T::Hash, pricing: PriceCurve<BalanceOf<T>>) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot pricing");
+            T::PricingModel::validate(&pricing, parking_lot.min_price, parking_lot.max_price)?;
+            for (_, pool) in parking_lot.categories.iter() {
+                T::PricingModel::validate(&pricing, pool.min_price, pool.max_price)?;
             }
 
-            <ParkingLotLastTime<T>>::insert(parking_lot_hash, now);
-            let mut accs = Self::current_parking_accounts(parking_lot_hash);
-            accs.push(user.clone());
-            parking_lot.remain -= 1;
+            // settle the fees accrued under the old price curve before changing it
+            let now = <timestamp::Module<T>>::get();
+            let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            parking_lot.pricing = pricing.clone();
+            parking_lot.update_prices()?;
 
-            // change states
-            <CurrentParkingAccounts<T>>::insert(parking_lot_hash, accs);
             <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
-            <UserParkingInfo<T>>::insert(user.clone(), parking_info.clone());
-
-            Self::deposit_event(RawEvent::Entering(<timestamp::Module<T>>::get(), parking_info));
+            Self::deposit_event(RawEvent::PricingChanged(now, parking_lot_hash, pricing));
             Ok(())
         }
 
-        /// User leaving
-        pub fn leaving(origin) -> Result {
-            let user = ensure_signed(origin)?;
-            let parking_info = Self::user_parking_info(user.clone()).ok_or("User has not entered a parking lot")?;
-            let parking_lot_hash = parking_info.parking_lot_hash.clone();
-            let mut parking_lot = Self::parking_lots(parking_lot_hash).expect("User must has the parking info. Qed");
-            let owner = parking_lot.owner.clone();
-            let accs: Vec<_> = Self::current_parking_accounts(parking_lot_hash);
-            let mut new_accs = vec![];
-            for acc in accs {
-                if acc != user {
-                    new_accs.push(acc);
+        /// Set the positions of a category with their own price range, they are taken from the standard positions.
+        /// Zero capacity removes the category, only the owner can do it
+        pub fn set_spot_category(
+            origin,
+            parking_lot_hash: T::Hash,
+            category: SpotCategory,
+            capacity: u32,
+            min_price: BalanceOf<T>,
+            max_price: BalanceOf<T>
+        ) -> Result {
+            let owner = ensure_signed(origin)?;
+            ensure!(category != SpotCategory::Standard, "Standard spots are the positions not in any category");
+            ensure!(min_price <= max_price, "min price must equal and less than max price");
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot categories");
+            T::PricingModel::validate(&parking_lot.pricing, min_price, max_price)?;
+
+            let old_capacity = parking_lot.pool(category).map_or(0, |pool| pool.capacity);
+            let occupied = parking_lot.pool(category).map_or(0, |pool| pool.capacity - pool.remain);
+            ensure!(capacity >= occupied, "Capacity cannot be less than the number of parked users");
+            // the positions of the category are taken from or given back to the standard positions
+            let standard_capacity = (parking_lot.standard_capacity() + old_capacity)
+                .checked_sub(capacity)
+                .ok_or("Not enough standard spots for the category")?;
+            let standard_parked = parking_lot.standard_capacity() - parking_lot.standard_remain();
+            let now = <timestamp::Module<T>>::get();
+            let bookings = Self::parking_lot_bookings(parking_lot_hash);
+            ensure!(
+                standard_capacity >= standard_parked
+                    && standard_capacity >= Self::peak_bookings(&bookings, now, T::Moment::max_value()),
+                "Not enough standard spots for the category"
+            );
+
+            // the numbered spots of the category follow its capacity
+            let spots = match Self::parking_lot_spots(parking_lot_hash) {
+                Some(mut spots) => {
+                    spots.assign_category(category, capacity)?;
+                    Some(spots)
+                }
+                None => None,
+            };
+
+            // settle the fees accrued under the old occupancy before changing it
+            let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            let pool = SpotPool {
+                capacity,
+                remain: capacity - occupied,
+                current_price: min_price,
+                min_price,
+                max_price,
+            };
+            if capacity == 0 {
+                parking_lot.categories.retain(|(pool_category, _)| *pool_category != category);
+                <CategoryFeeIndex<T>>::remove((parking_lot_hash, category));
+            } else {
+                match parking_lot.pool_mut(category) {
+                    Some(old_pool) => *old_pool = pool.clone(),
+                    None => parking_lot.categories.push((category, pool.clone())),
                 }
             }
+            parking_lot.update_prices()?;
+            let pool = parking_lot.pool(category).cloned().unwrap_or(pool);
 
-            // update fees first, and then pay the fee and remove parking info
-            // change states
-            Self::pay_parking_fee(user.clone(), &parking_lot)?;
-            parking_lot.remain += 1;
-            <CurrentParkingAccounts<T>>::insert(parking_lot_hash, new_accs);
-            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot.clone());
-            <UserParkingInfo<T>>::remove(user.clone());
-            Self::deposit_event(RawEvent::Leaving(<timestamp::Module<T>>::get(), user, owner, parking_info));
+            if let Some(spots) = spots {
+                <ParkingLotSpots<T>>::insert(parking_lot_hash, spots);
+            }
+            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+            Self::deposit_event(RawEvent::SpotCategoryChanged(now, parking_lot_hash, category, pool));
             Ok(())
         }
 
-    }
-}
+        /// Change the weekly price schedule of a parking lot, an empty schedule always charges the price
+        /// for the occupancy. Only the owner can do it
+        pub fn set_price_schedule(
+            origin,
+            parking_lot_hash: T::Hash,
+            schedule: Vec<ScheduleEntry<BalanceOf<T>>>
+        ) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot schedule");
+            validate_schedule(&schedule)?;
 
-impl<T: Trait> Module<T> {
+            // settle the fees accrued under the old schedule before changing it
+            let now = <timestamp::Module<T>>::get();
+            Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            if schedule.is_empty() {
+                <ParkingLotSchedule<T>>::remove(parking_lot_hash);
+            } else {
+                <ParkingLotSchedule<T>>::insert(parking_lot_hash, schedule.clone());
+            }
 
-    fn _new_parking_lot(owner: T::AccountId, parking: ParkingLot<T>) -> Result {
-        let count = Self::owner_parking_lots_count(owner.clone());
-        let all = Self::all_parking_lots_count();
+            Self::deposit_event(RawEvent::PriceScheduleChanged(now, parking_lot_hash, schedule));
+            Ok(())
+        }
 
-        let parking_lot_hash =
-            (<system::Module<T>>::random_seed(), &owner, count, all).using_encoded(<T as system::Trait>::Hashing::hash);
+        /// Change the grace period and the fee caps of a parking lot, only the owner can do it
+        pub fn set_fee_rules(origin, parking_lot_hash: T::Hash, rules: FeeRules<T::Moment, BalanceOf<T>>) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot fee rules");
 
-        let now = <timestamp::Module<T>>::get();
+            <ParkingLotFeeRules<T>>::insert(parking_lot_hash, rules);
+            Self::deposit_event(RawEvent::FeeRulesChanged(<timestamp::Module<T>>::get(), parking_lot_hash, rules));
+            Ok(())
+        }
 
-        <ParkingLots<T>>::insert(parking_lot_hash, parking.clone());
-        <ParkingLotsByIndex<T>>::insert(all, parking_lot_hash);
-        <ParkingLotLastTime<T>>::insert(parking_lot_hash, now);
-        <OwnerParkingLotsArray<T>>::insert((owner.clone(), count), parking_lot_hash);
-        <OwnerParkingLotsCount<T>>::insert(&owner, count + 1);
-        AllParkingLotsCount::put(all + 1);
-        Ok(())
-    }
+        /// Change how the parking time of a parking lot is billed, it applies to the parked users as well.
+        /// Only the owner can do it
+        pub fn set_billing(origin, parking_lot_hash: T::Hash, billing: Billing) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot billing");
 
+            <ParkingLotBilling<T>>::insert(parking_lot_hash, billing);
+            Self::deposit_event(RawEvent::BillingChanged(<timestamp::Module<T>>::get(), parking_lot_hash, billing));
+            Ok(())
+        }
 
-    /// Pay parking fee when user leaving
-    fn pay_parking_fee(user: T::AccountId, parking_lot: &ParkingLot<T>) -> Result {
-        let parking_info = Self::user_parking_info(user.clone()).ok_or("User must be in the parking lot")?;
-        ensure!(parking_info.user_id == user, "User must be in the parking lot");
+        /// Enable or disable locked rates of a parking lot, the users entering it with locked rates pay the price
+        /// at entering for the whole stay. It does not change the parked users, only the owner can do it
+        pub fn set_locked_rate(origin, parking_lot_hash: T::Hash, locked: bool) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot rates");
 
-        let owner = parking_lot.owner.clone();
-        let now = <timestamp::Module<T>>::get();
-        let parking_lot_hash = parking_info.parking_lot_hash.clone();
-        let old_time = parking_info.current_time.clone();
-        Self::refresh_all_fee(parking_lot, parking_lot_hash, now, old_time)?;
-        // Recompute all fees before paying
-        let new_parking_info = Self::user_parking_info(user.clone()).expect("User must be existed. Qed");
-        if user == owner {
+            if locked {
+                <ParkingLotLockedRate<T>>::insert(parking_lot_hash, true);
+            } else {
+                <ParkingLotLockedRate<T>>::remove(parking_lot_hash);
+            }
+            Self::deposit_event(RawEvent::LockedRateChanged(<timestamp::Module<T>>::get(), parking_lot_hash, locked));
             Ok(())
-        } else {
-            T::Currency::transfer(&user, &owner, new_parking_info.current_fee.clone())
         }
-    }
 
-    /// Recompute all parking fees and current price for current parking lot
-    fn refresh_all_fee(
-        parking_lot: &ParkingLot<T>,
-        parking_lot_hash: T::Hash,
-        new_time: T::Moment,
-        old_time: T::Moment,
-    ) -> Result {
-        let mut parking_lot = parking_lot.clone();
-        let (new_fee, current_price) = parking_lot.compute_new_fee(new_time, old_time)?;
-        let accs: Vec<_> = Self::current_parking_accounts(parking_lot_hash);
-        // refresh current price for parking lot
-        parking_lot.current_price = current_price;
-        <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+        /// Change the overstay rate charged after the prepaid time of the sessions entering the parking lot,
+        /// without a rate the parking lot takes no prepaid sessions. Only the owner can do it
+        pub fn set_overstay_rate(origin, parking_lot_hash: T::Hash, rate: Option<BalanceOf<T>>) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot overstay rate");
 
-        // refresh all users' fee
-        for acc in accs {
-            let mut parking_info = Self::user_parking_info(acc.clone()).ok_or("User not exists")?;
-            parking_info.current_time = new_time;
-            parking_info.current_fee += new_fee;
-            <UserParkingInfo<T>>::insert(acc, parking_info);
+            match rate {
+                Some(rate) => <ParkingLotOverstayRate<T>>::insert(parking_lot_hash, rate),
+                None => <ParkingLotOverstayRate<T>>::remove(parking_lot_hash),
+            }
+            Self::deposit_event(RawEvent::OverstayRateChanged(<timestamp::Module<T>>::get(), parking_lot_hash, rate));
+            Ok(())
         }
 
-        Ok(())
-    }
-}
+        /// Add a pass product to a parking lot or change one, `product` is its index and the next index adds it.
+        /// The valid passes are not changed, only the owner can do it
+        pub fn set_pass_product(
+            origin,
+            parking_lot_hash: T::Hash,
+            product: u32,
+            price: BalanceOf<T>,
+            duration: T::Moment,
+            limit: u32
+        ) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot passes");
+            ensure!(!duration.is_zero(), "Pass duration must not be zero");
+            let mut products = Self::pass_products(parking_lot_hash);
+            let index = product as usize;
+            ensure!(index <= products.len(), "The pass product has not existed");
+            ensure!(index < MAX_PASS_PRODUCTS, "Too many pass products");
 
-/// tests for this module
-#[cfg(test)]
-mod tests {
-    use super::*;
+            let sold = products.get(index).map_or(0, |product| product.sold);
+            let pass_product = PassProduct {
+                price,
+                duration,
+                limit,
+                sold,
+            };
+            if index == products.len() {
+                products.push(pass_product.clone());
+            } else {
+                products[index] = pass_product.clone();
+            }
 
-    use primitives::{Blake2Hasher, H256};
-    use runtime_io::{with_externalities, TestExternalities};
-    use sr_primitives::weights::Weight;
-    use sr_primitives::Perbill;
-    use sr_primitives::{
-        testing::Header,
-        traits::{BlakeTwo256, IdentityLookup},
-    };
-    use support::{assert_err, assert_ok, impl_outer_origin, parameter_types};
+            <PassProducts<T>>::insert(parking_lot_hash, products);
+            Self::deposit_event(RawEvent::PassProductChanged(
+                <timestamp::Module<T>>::get(),
+                parking_lot_hash,
+                product,
+                pass_product,
+            ));
+            Ok(())
+        }
 
-    impl_outer_origin! {
-        pub enum Origin for Test {}
-    }
+        /// Issue a coupon for one of the owner's parking lots, or all of them without a parking lot. The coupon is
+        /// stored by the hash of its promo code so that the code is revealed only when a user applies it
+        pub fn issue_coupon(
+            origin,
+            code_hash: T::Hash,
+            parking_lot_hash: Option<T::Hash>,
+            discount: Discount<BalanceOf<T>>,
+            uses: u32,
+            expires: T::Moment
+        ) -> Result {
+            let owner = ensure_signed(origin)?;
+            if let Some(parking_lot_hash) = parking_lot_hash {
+                let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+                ensure!(parking_lot.owner == owner, "Only the owner can issue coupons of the parking lot");
+            }
+            if let Discount::Percent(percent) = discount {
+                ensure!(percent <= 100, "Discount percent cannot be greater than 100");
+            }
+            ensure!(uses > 0, "Coupon uses must not be zero");
+            let now = <timestamp::Module<T>>::get();
+            ensure!(expires > now, "Coupon expiry must be in the future");
+            ensure!(!<Coupons<T>>::exists(code_hash), "The coupon has existed");
 
-    // For testing the module, we construct most of a mock runtime. This means
-    // first constructing a configuration type (`Test`) which `impl`s each of the
-    // configuration traits of modules we want to use.
-    #[derive(Clone, Eq, PartialEq)]
-    pub struct Test;
+            let coupon = Coupon {
+                owner,
+                parking_lot_hash,
+                discount,
+                uses_left: uses,
+                expires,
+            };
+            <Coupons<T>>::insert(code_hash, coupon.clone());
+            if let Some(parking_lot_hash) = parking_lot_hash {
+                <ParkingLotCoupons<T>>::mutate(parking_lot_hash, |coupons| coupons.push(code_hash));
+            }
+            Self::deposit_event(RawEvent::CouponIssued(now, code_hash, coupon));
+            Ok(())
+        }
 
-    parameter_types! {
-        pub const BlockHashCount: u64 = 250;
-        pub const MaximumBlockWeight: Weight = 1024;
-        pub const MaximumBlockLength: u32 = 2 * 1024;
-        pub const AvailableBlockRatio: Perbill = Perbill::from_percent(75);
-    }
+        /// Revoke a coupon, the sessions it is applied to keep the discount. Only the owner who issued it can do it
+        pub fn revoke_coupon(origin, code_hash: T::Hash) -> Result {
+            let owner = ensure_signed(origin)?;
+            let coupon = Self::coupons(code_hash).ok_or("The coupon has not existed")?;
+            ensure!(coupon.owner == owner, "Only the owner who issued the coupon can revoke it");
 
-    impl system::Trait for Test {
-        type Origin = Origin;
-        type Call = ();
-        type Index = u64;
-        type BlockNumber = u64;
-        type Hash = H256;
-        type Hashing = BlakeTwo256;
-        type AccountId = u64;
-        type Lookup = IdentityLookup<Self::AccountId>;
-        type Header = Header;
-        type WeightMultiplierUpdate = ();
-        type Event = ();
-        type BlockHashCount = BlockHashCount;
-        type MaximumBlockWeight = MaximumBlockWeight;
-        type MaximumBlockLength = MaximumBlockLength;
-        type AvailableBlockRatio = AvailableBlockRatio;
-        type Version = ();
-    }
+            <Coupons<T>>::remove(code_hash);
+            if let Some(parking_lot_hash) = coupon.parking_lot_hash {
+                <ParkingLotCoupons<T>>::mutate(parking_lot_hash, |coupons| coupons.retain(|hash| *hash != code_hash));
+            }
+            Self::deposit_event(RawEvent::CouponRevoked(<timestamp::Module<T>>::get(), code_hash));
+            Ok(())
+        }
 
-    parameter_types! {
-        pub const ExistentialDeposit: u64 = 0;
-        pub const TransferFee: u64 = 0;
-        pub const CreationFee: u64 = 0;
-        pub const TransactionBaseFee: u64 = 0;
-        pub const TransactionByteFee: u64 = 0;
-    }
+        /// Start or stop numbering the spots of a parking lot, only the owner can do it when no one is parked.
+        /// Users are assigned a free spot of their category at entering while the spots are numbered
+        pub fn set_spot_numbering(origin, parking_lot_hash: T::Hash, numbered: bool) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot spots");
+            ensure!(
+                Self::current_parking_count(parking_lot_hash) == 0,
+                "The parking lot still has parked users"
+            );
+            let spots = Self::parking_lot_spots(parking_lot_hash);
+            ensure!(spots.is_some() != numbered, "The spots of the parking lot are already so");
 
-    impl balances::Trait for Test {
-        type Balance = u64;
-        type OnFreeBalanceZero = ();
-        type OnNewAccount = ();
-        type Event = ();
-        type TransactionPayment = ();
-        type TransferPayment = ();
-        type DustRemoval = ();
-        type ExistentialDeposit = ExistentialDeposit;
-        type TransferFee = TransferFee;
-        type CreationFee = CreationFee;
-        type TransactionBaseFee = TransactionBaseFee;
-        type TransactionByteFee = TransactionByteFee;
-        type WeightToFee = ();
-    }
+            let now = <timestamp::Module<T>>::get();
+            if numbered {
+                // the lowest spots are given to the categories in their order
+                let mut spots = SpotMap::new(parking_lot.capacity);
+                for (category, pool) in parking_lot.categories.iter() {
+                    spots.assign_category(*category, pool.capacity)?;
+                }
+                <ParkingLotSpots<T>>::insert(parking_lot_hash, spots);
+            } else if let Some(spots) = spots {
+                // the spots out of service are positions of the parking lot again
+                let out_of_service = spots.out_of_service_count();
+                if out_of_service > 0 {
+                    let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+                    parking_lot.capacity += out_of_service;
+                    parking_lot.remain += out_of_service;
+                    parking_lot.update_prices()?;
+                    <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+                }
+                <ParkingLotSpots<T>>::remove(parking_lot_hash);
+            }
 
-    parameter_types! {
-        pub const MinimumPeriod: u64 = 1000;
-    }
+            Self::deposit_event(RawEvent::SpotNumberingChanged(now, parking_lot_hash, numbered));
+            Ok(())
+        }
 
-    impl timestamp::Trait for Test {
-        type Moment = u64;
-        type OnTimestampSet = ();
-        type MinimumPeriod = MinimumPeriod;
-    }
+        /// Take a free spot out of service or put it back into service, a spot out of service is a standard
+        /// position removed from the capacity. Only the owner can do it
+        pub fn set_spot_in_service(origin, parking_lot_hash: T::Hash, spot: u32, in_service: bool) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot spots");
+            let mut spots =
+                Self::parking_lot_spots(parking_lot_hash).ok_or("The spots of the parking lot are not numbered")?;
+            ensure!(spot < spots.count, "The spot has not existed");
+            ensure!(spots.is_out_of_service(spot) == in_service, "The spot is already so");
 
-    impl Trait for Test {
-        type Event = ();
-        type Currency = balances::Module<Test>;
-    }
+            let now = <timestamp::Module<T>>::get();
+            if !in_service {
+                ensure!(!spots.is_occupied(spot), "The spot is occupied");
+                ensure!(
+                    spots.category(spot) == SpotCategory::Standard,
+                    "Only standard spots can be taken out of service"
+                );
+                // the position must be neither reserved nor booked
+                let bookings = Self::parking_lot_bookings(parking_lot_hash);
+                let booked = Self::peak_bookings(&bookings, now, T::Moment::max_value());
+                ensure!(
+                    parking_lot.standard_remain() > Self::unheld_bookings(parking_lot_hash, now)
+                        && booked + Self::sold_passes(parking_lot_hash) < parking_lot.standard_capacity(),
+                    "Not enough standard spots to take out of service"
+                );
+            }
 
-    type Parking = Module<Test>;
+            let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            if in_service {
+                parking_lot.capacity += 1;
+                parking_lot.remain += 1;
+            } else {
+                parking_lot.capacity -= 1;
+                parking_lot.remain -= 1;
+            }
+            parking_lot.update_prices()?;
+            spots.set_out_of_service(spot, !in_service);
 
-    // This function basically just builds a genesis storage key/value store according to
-    // our desired mockup.
-    fn new_test_ext() -> TestExternalities<Blake2Hasher> {
-        let mut t = system::GenesisConfig::default().build_storage::<Test>().unwrap();
-        GenesisConfig::<Test>::default().assimilate_storage(&mut t).unwrap();
-        // or
-        // t.0.extend(GenesisConfig::<Test>::default().build_storage().unwrap().0);
+            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+            <ParkingLotSpots<T>>::insert(parking_lot_hash, spots);
+            Self::deposit_event(RawEvent::SpotServiceChanged(now, parking_lot_hash, spot, in_service));
+            Ok(())
+        }
 
-        GenesisConfig::<Test> {
-            parking_lots: vec![(0, 10, 10, 10, 10, 100, 60, 60), (1, 100, 1, 100, 100, 100, 61, 61)],
+        /// Change the status of a parking lot, only the owner can do it
+        pub fn set_parking_lot_status(origin, parking_lot_hash: T::Hash, status: LotStatus) -> Result {
+            let owner = ensure_signed(origin)?;
+            let mut parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the parking lot status");
+
+            parking_lot.status = status;
+            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+            Self::deposit_event(RawEvent::ParkingLotStatusChanged(<timestamp::Module<T>>::get(), parking_lot_hash, status));
+            Ok(())
         }
-        .assimilate_storage(&mut t)
-        .unwrap();
-        t.into()
-    }
 
-    #[test]
-    fn test_new_parking_lot() {
-        with_externalities(&mut new_test_ext(), || {
-            let user = 0;
-            assert_eq!(Parking::all_parking_lots_count(), 2);
-            assert_ok!(Parking::new_parking_lot(Origin::signed(user), 50, 50, 100, 50, 100,));
-            assert_eq!(Parking::all_parking_lots_count(), 3);
-            assert_eq!(Parking::owner_parking_lots_count(user), 2);
-            assert_eq!(Parking::owner_parking_lots_count(1), 1);
-        })
-    }
+        /// Remove an empty parking lot permanently with the coupons issued for it, only the owner can do it
+        pub fn remove_parking_lot(origin, parking_lot_hash: T::Hash) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can remove the parking lot");
+            ensure!(
+                parking_lot.remain == parking_lot.capacity && Self::current_parking_count(parking_lot_hash) == 0,
+                "The parking lot still has parked users"
+            );
+            ensure!(
+                Self::parking_lot_bookings(parking_lot_hash).is_empty(),
+                "The parking lot still has bookings"
+            );
 
-    #[test]
-    fn test_entering_and_leving() {
-        with_externalities(&mut new_test_ext(), || {
-            let user = 1;
-            assert_eq!(Parking::all_parking_lots_count(), 2);
+            Self::_remove_parking_lot(&owner, parking_lot_hash);
+            Self::deposit_event(RawEvent::ParkingLotRemoved(<timestamp::Module<T>>::get(), owner, parking_lot_hash));
+            Ok(())
+        }
 
-            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
-            assert_eq!(Parking::parking_lots_by_index(0), parking_lot_hash);
-            assert_ok!(Parking::entering(Origin::signed(user), parking_lot_hash.clone()));
+        /// Transfer a parking lot to another owner, fees accrued before the handover are paid to the old owner
+        pub fn transfer_parking_lot(origin, parking_lot_hash: T::Hash, new_owner: T::AccountId) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can transfer the parking lot");
+            ensure!(new_owner != owner, "The parking lot already belongs to the account");
 
-            let parking_info = Parking::user_parking_info(user).unwrap();
-            assert_eq!(parking_info.user_id, user);
-            assert_eq!(parking_info.current_fee, 0);
-            assert_eq!(parking_info.current_time.clone(), parking_info.enter_time.clone());
-            assert_err!(
-                Parking::entering(Origin::signed(user), parking_lot_hash.clone()),
-                "User already has entered a parking lot"
-            );
-            assert_ok!(Parking::leaving(Origin::signed(user)));
+            let now = <timestamp::Module<T>>::get();
+            let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            Self::settle_accrued_fees(&owner, &Self::current_parking_accounts(parking_lot_hash), now);
 
-            assert_ok!(Parking::entering(Origin::signed(user), parking_lot_hash.clone()));
-            let parking_info = Parking::user_parking_info(user).unwrap();
-            assert_eq!(parking_info.user_id, user);
-            assert_eq!(parking_info.current_fee, 0);
-            assert_eq!(parking_info.current_time.clone(), parking_info.enter_time.clone());
-            assert_err!(
-                Parking::entering(Origin::signed(user), parking_lot_hash.clone()),
-                "User already has entered a parking lot"
-            );
-            assert_ok!(Parking::leaving(Origin::signed(user)));
+            Self::remove_owner_parking_lot(&owner, parking_lot_hash);
+            Self::add_owner_parking_lot(&new_owner, parking_lot_hash);
+            parking_lot.owner = new_owner.clone();
+            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+
+            Self::deposit_event(RawEvent::ParkingLotTransferred(now, parking_lot_hash, owner, new_owner));
+            Ok(())
+        }
+
+        /// User entering by parking lot hash, parking in a position of the category. It fails if the price at
+        /// inclusion is above `max_price_per_second` or the booked amount is above `max_total`, unless the action
+        /// is `Ignore` the session is watched for exceeding them later. The promo code of a coupon discounts
+        /// the fee paid at leaving
+        pub fn entering(
+            origin,
+            parking_lot_hash: T::Hash,
+            category: SpotCategory,
+            max_price_per_second: BalanceOf<T>,
+            max_total: Option<BalanceOf<T>>,
+            action: GuardAction,
+            coupon: Option<Vec<u8>>
+        ) -> Result {
+            let user = ensure_signed(origin)?;
+            let guard = PriceGuard {
+                max_price_per_second,
+                max_total,
+                action,
+            };
+            Self::_entering(user, parking_lot_hash, category, guard, None, coupon)
+        }
+
+        /// User entering a standard position of the parking lot and paying `minutes` up front at the price
+        /// at inclusion, the stay after the prepaid time is charged at the overstay rate of the parking lot
+        pub fn entering_prepaid(origin, parking_lot_hash: T::Hash, minutes: u32) -> Result {
+            let user = ensure_signed(origin)?;
+            Self::_entering(user, parking_lot_hash, SpotCategory::Standard, Default::default(), Some(minutes), None)
+        }
+
+        /// Extend the prepaid time of the user's session by `minutes` at the current price of the parking lot,
+        /// the extension starts when the prepaid time ends, or now with the overstay paid if it has run out
+        pub fn extend(origin, minutes: u32) -> Result {
+            let user = ensure_signed(origin)?;
+            let mut parking_info = Self::user_parking_info(&user).ok_or("User has not entered a parking lot")?;
+            let paid_until = parking_info.paid_until.ok_or("The parking session is not prepaid")?;
+            let parking_lot_hash = parking_info.parking_lot_hash;
+            let parking_lot = Self::parking_lots(parking_lot_hash).expect("User must has the parking info. Qed");
+
+            let now = <timestamp::Module<T>>::get();
+            let start = cmp::max(paid_until, now);
+            let end = prepaid_end::<T>(start, minutes)?;
+            let schedule = Self::parking_lot_schedule(parking_lot_hash);
+            let amount = scheduled_fee::<T>(&schedule, parking_lot.category_price(parking_info.category)?, start, end)?;
+            Self::accrue_user_fee(&mut parking_info, now)?;
+            let overstay = parking_info.current_fee.checked_sub(&parking_info.paid_fee).unwrap_or_else(Zero::zero);
+            let total = overstay.checked_add(&amount).ok_or("Fee overflow")?;
+            T::Currency::reserve(&user, total).map_err(|_| "Not enough balance for the prepaid time")?;
+
+            let mut reserved = total;
+            Self::collect_fee(&user, &parking_lot.owner, parking_lot_hash, total, &mut reserved);
+            T::Currency::unreserve(&user, reserved);
+            // the fee so far is paid, the extension is paid up front on top of it
+            parking_info.base_fee = parking_info.current_fee.checked_add(&amount).ok_or("Fee overflow")?;
+            parking_info.metered_fee = 0;
+            parking_info.current_fee = parking_info.base_fee;
+            parking_info.paid_fee = parking_info.base_fee;
+            parking_info.paid_until = Some(end);
+
+            <UserParkingInfo<T>>::insert(&user, parking_info);
+            Self::remove_prepaid_end(paid_until, &user);
+            Self::add_prepaid_end(end, &user);
+            Self::deposit_event(RawEvent::PrepaidExtended(now, user, end, total));
+            Ok(())
+        }
+
+        /// Buy a pass product of the parking lot, the pass holds a standard position until it expires and
+        /// the user enters and leaves free of charge meanwhile
+        pub fn buy_pass(origin, parking_lot_hash: T::Hash, product: u32, auto_renew: bool) -> Result {
+            let user = ensure_signed(origin)?;
+            ensure!(
+                !<UserPasses<T>>::exists((user.clone(), parking_lot_hash)),
+                "User already has a pass for the parking lot"
+            );
+            ensure!(Self::user_debts(&user).is_empty(), "User has outstanding parking debt");
+            ensure!(
+                Self::user_parking_info(&user).map_or(true, |info| info.parking_lot_hash != parking_lot_hash),
+                "User is parked in the parking lot"
+            );
+
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.status == LotStatus::Open, "The parking lot is not open");
+            let mut products = Self::pass_products(parking_lot_hash);
+            let pass_product = products.get_mut(product as usize).ok_or("The pass product has not existed")?;
+            ensure!(pass_product.sold < pass_product.limit, "The pass product is sold out");
+            let now = <timestamp::Module<T>>::get();
+            ensure!(
+                parking_lot.standard_remain() > Self::unheld_bookings(parking_lot_hash, now),
+                "The parking lot has no more position"
+            );
+            let expires = now.checked_add(&pass_product.duration).ok_or("Time overflow")?;
+            // the passes and the bookings hold positions ahead, together they cannot exceed the standard capacity
+            let bookings = Self::parking_lot_bookings(parking_lot_hash);
+            ensure!(
+                Self::peak_bookings(&bookings, now, expires) + Self::sold_passes(parking_lot_hash)
+                    < parking_lot.standard_capacity(),
+                "The parking lot is fully booked"
+            );
+            let price = pass_product.price;
+            T::Currency::reserve(&user, price).map_err(|_| "Not enough balance for the pass")?;
+
+            // the position of the pass counts as occupied for the price
+            let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            parking_lot.occupy(SpotCategory::Standard)?;
+            pass_product.sold += 1;
+            let mut reserved = price;
+            Self::collect_fee(&user, &parking_lot.owner, parking_lot_hash, price, &mut reserved);
+            T::Currency::unreserve(&user, reserved);
+
+            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+            <PassProducts<T>>::insert(parking_lot_hash, products);
+            <UserPasses<T>>::insert((user.clone(), parking_lot_hash), Pass {
+                product,
+                expires,
+                auto_renew,
+            });
+            Self::add_pass_expiry(expires, &user, parking_lot_hash);
+
+            Self::deposit_event(RawEvent::PassBought(now, user, parking_lot_hash, product, expires));
+            Ok(())
+        }
+
+        /// Turn the auto-renewal of the user's pass for the parking lot on or off
+        pub fn set_pass_auto_renew(origin, parking_lot_hash: T::Hash, auto_renew: bool) -> Result {
+            let user = ensure_signed(origin)?;
+            let mut pass =
+                Self::user_passes((user.clone(), parking_lot_hash)).ok_or("User has no pass for the parking lot")?;
+
+            pass.auto_renew = auto_renew;
+            <UserPasses<T>>::insert((user.clone(), parking_lot_hash), pass);
+            Self::deposit_event(RawEvent::PassRenewalChanged(
+                <timestamp::Module<T>>::get(),
+                user,
+                parking_lot_hash,
+                auto_renew,
+            ));
+            Ok(())
+        }
+
+        /// User leaving, with the promo code of a coupon if none was applied at entering
+        pub fn leaving(origin, coupon: Option<Vec<u8>>) -> Result {
+            let user = ensure_signed(origin)?;
+            Self::_leaving(user, <timestamp::Module<T>>::get(), coupon)
+        }
+
+        /// Reserve a position of the parking lot until the arriving deadline, the reservation deposit
+        /// is returned at entering and forfeited to the owner if the user does not arrive in time
+        pub fn reserve_spot(origin, parking_lot_hash: T::Hash, arrive_by: T::Moment) -> Result {
+            let user = ensure_signed(origin)?;
+            ensure!(!<UserParkingInfo<T>>::exists(&user), "User already has entered a parking lot");
+            ensure!(!<Reservations<T>>::exists(&user), "User already has reserved a parking lot");
+            ensure!(Self::user_debts(&user).is_empty(), "User has outstanding parking debt");
+
+            let now = <timestamp::Module<T>>::get();
+            ensure!(arrive_by > now, "Arriving deadline must be in the future");
+            ensure!(
+                arrive_by - now <= T::MaxReservationPeriod::get(),
+                "Arriving deadline is too far in the future"
+            );
+
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.status == LotStatus::Open, "The parking lot is not open");
+            ensure!(
+                parking_lot.standard_remain() > Self::unheld_bookings(parking_lot_hash, now),
+                "The parking lot has no more position"
+            );
+
+            // a reserved position is a standard one and counts as occupied for the price
+            let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+            parking_lot.occupy(SpotCategory::Standard)?;
+
+            let deposit = T::ReservationDeposit::get();
+            T::Currency::reserve(&user, deposit).map_err(|_| "Not enough balance for the reservation deposit")?;
+
+            <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+            <Reservations<T>>::insert(&user, Reservation {
+                parking_lot_hash,
+                arrive_by,
+                deposit,
+            });
+            Self::add_reservation_deadline(arrive_by, &user);
+
+            Self::deposit_event(RawEvent::SpotReserved(now, user, parking_lot_hash, arrive_by));
+            Ok(())
+        }
+
+        /// Book time windows of the standard positions of the parking lot at its current price, the amount of all
+        /// windows is reserved until the user enters during a window. A window is rejected if the bookings and
+        /// the passes would hold more positions than the standard capacity at any moment of it. A position is held
+        /// for a window when it starts, the amount is refunded if walk-ins have taken all positions by then
+        pub fn book(origin, parking_lot_hash: T::Hash, windows: Vec<(T::Moment, T::Moment)>) -> Result {
+            let user = ensure_signed(origin)?;
+            ensure!(!windows.is_empty(), "No booking window");
+            ensure!(windows.len() <= MAX_BOOKING_WINDOWS, "Too many booking windows");
+            ensure!(Self::user_debts(&user).is_empty(), "User has outstanding parking debt");
+
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.status == LotStatus::Open, "The parking lot is not open");
+
+            let now = <timestamp::Module<T>>::get();
+            let seed = <system::Module<T>>::random_seed();
+            let mut lot_bookings = Self::parking_lot_bookings(parking_lot_hash);
+            let schedule = Self::parking_lot_schedule(parking_lot_hash);
+            let mut bookings = Vec::with_capacity(windows.len());
+            let passes = Self::sold_passes(parking_lot_hash);
+            let mut total: BalanceOf<T> = Zero::zero();
+            // check all windows first, the windows of this call count against the capacity as well
+            for (start, end) in windows {
+                ensure!(start > now, "Booking window must be in the future");
+                ensure!(end > start, "Booking window must end after it starts");
+                ensure!(
+                    Self::peak_bookings(&lot_bookings, start, end) + passes < parking_lot.standard_capacity(),
+                    "The parking lot is fully booked"
+                );
+
+                let amount = scheduled_fee::<T>(&schedule, parking_lot.current_price, start, end)?;
+                total = total.checked_add(&amount).ok_or("Booking amount overflow")?;
+                let booking_id =
+                    (seed, &user, parking_lot_hash, start, end).using_encoded(<T as system::Trait>::Hashing::hash);
+                ensure!(
+                    !lot_bookings.iter().any(|(_, _, id)| *id == booking_id),
+                    "Booking hash collision"
+                );
+
+                lot_bookings.push((start, end, booking_id));
+                bookings.push((booking_id, Booking {
+                    user: user.clone(),
+                    parking_lot_hash,
+                    start,
+                    end,
+                    price: parking_lot.current_price,
+                    amount,
+                    held: false,
+                }));
+            }
+
+            T::Currency::reserve(&user, total).map_err(|_| "Not enough balance for the booking")?;
+
+            <ParkingLotBookings<T>>::insert(parking_lot_hash, lot_bookings);
+            for (booking_id, booking) in bookings {
+                <UserBookings<T>>::mutate(&user, |ids| ids.push(booking_id));
+                Self::add_booking_start(booking.start, booking_id);
+                Self::add_booking_end(booking.end, booking_id);
+                <Bookings<T>>::insert(booking_id, booking.clone());
+                Self::deposit_event(RawEvent::Booked(now, booking_id, booking));
+            }
+            Ok(())
+        }
+
+        /// Cancel a booking before its window starts, the refund follows the booking rules of the parking lot
+        pub fn cancel_booking(origin, booking_id: T::Hash) -> Result {
+            let user = ensure_signed(origin)?;
+            let booking = Self::bookings(booking_id).ok_or("The booking has not existed")?;
+            ensure!(booking.user == user, "Only the user can cancel the booking");
+            let now = <timestamp::Module<T>>::get();
+            ensure!(now < booking.start, "The booking window has started");
+
+            let rules = Self::parking_lot_booking_rules(booking.parking_lot_hash);
+            let refund = if booking.start - now >= rules.free_cancel_notice {
+                booking.amount
+            } else {
+                Permill::from_percent(rules.late_refund_percent.into()) * booking.amount
+            };
+            // a parking lot cannot be removed while it has bookings
+            let parking_lot =
+                Self::parking_lots(booking.parking_lot_hash).expect("Booked parking lot must be existed. Qed");
+
+            Self::remove_booking(booking_id, &booking);
+            Self::release_booking_hold(&booking, now);
+            let penalty = booking.amount - refund;
+            let mut reserved = penalty;
+            Self::collect_fee(&user, &parking_lot.owner, booking.parking_lot_hash, penalty, &mut reserved);
+            T::Currency::unreserve(&user, refund + reserved);
+
+            Self::deposit_event(RawEvent::BookingCancelled(now, booking_id, refund));
+            Ok(())
+        }
+
+        /// Change the refund rules for cancelling bookings of a parking lot, only the owner can do it
+        pub fn set_booking_rules(origin, parking_lot_hash: T::Hash, rules: BookingRules<T::Moment>) -> Result {
+            let owner = ensure_signed(origin)?;
+            let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+            ensure!(parking_lot.owner == owner, "Only the owner can change the booking rules");
+            ensure!(rules.late_refund_percent <= 100, "Refund percent cannot be greater than 100");
+
+            <ParkingLotBookingRules<T>>::insert(parking_lot_hash, rules);
+            Self::deposit_event(RawEvent::BookingRulesChanged(<timestamp::Module<T>>::get(), parking_lot_hash, rules));
+            Ok(())
+        }
+
+        /// Pay the whole debt owed to the creditor
+        pub fn pay_debt(origin, creditor: T::AccountId) -> Result {
+            let user = ensure_signed(origin)?;
+            let mut debts = Self::user_debts(&user);
+            let position = debts
+                .iter()
+                .position(|(owner, _)| *owner == creditor)
+                .ok_or("User has no debt to the account")?;
+
+            let (_, amount) = debts[position].clone();
+            T::Currency::transfer(&user, &creditor, amount)?;
+            debts.swap_remove(position);
+            if debts.is_empty() {
+                <UserDebts<T>>::remove(&user);
+            } else {
+                <UserDebts<T>>::insert(&user, debts);
+            }
+
+            Self::deposit_event(RawEvent::DebtSettled(<timestamp::Module<T>>::get(), user, creditor, amount));
+            Ok(())
+        }
+
+        /// Set the commission of all parking lots, or clear it with `None` to fall back to `Trait::Commission`,
+        /// only the sudo key can do it
+        pub fn set_commission(origin, commission: Option<Permill>) -> Result {
+            ensure_root(origin)?;
+
+            match commission {
+                Some(commission) => PlatformCommission::put(commission),
+                None => PlatformCommission::kill(),
+            }
+            Self::deposit_event(RawEvent::CommissionChanged(<timestamp::Module<T>>::get(), None, commission));
+            Ok(())
+        }
+
+        /// Set the commission of a parking lot, or clear it with `None` to fall back to the commission of all
+        /// parking lots, only the sudo key can do it
+        pub fn set_lot_commission(origin, parking_lot_hash: T::Hash, commission: Option<Permill>) -> Result {
+            ensure_root(origin)?;
+            ensure!(<ParkingLots<T>>::exists(parking_lot_hash), "The parking lot has not existed");
+
+            match commission {
+                Some(commission) => <ParkingLotCommission<T>>::insert(parking_lot_hash, commission),
+                None => <ParkingLotCommission<T>>::remove(parking_lot_hash),
+            }
+            Self::deposit_event(RawEvent::CommissionChanged(
+                <timestamp::Module<T>>::get(),
+                Some(parking_lot_hash),
+                commission,
+            ));
+            Ok(())
+        }
+
+        /// Pay the balance from the treasury to the account, only the sudo key can do it
+        pub fn spend_treasury(origin, dest: T::AccountId, amount: BalanceOf<T>) -> Result {
+            ensure_root(origin)?;
+
+            T::Currency::transfer(&Self::treasury_account(), &dest, amount)?;
+            Self::deposit_event(RawEvent::TreasurySpent(<timestamp::Module<T>>::get(), dest, amount));
+            Ok(())
+        }
+
+    }
+}
+
+impl<T: Trait> Module<T> {
+
+    fn _new_parking_lot(owner: T::AccountId, parking: ParkingLot<T>) -> Result {
+        let count = Self::owner_parking_lots_count(owner.clone());
+        let all = Self::all_parking_lots_count();
+
+        let parking_lot_hash =
+            (<system::Module<T>>::random_seed(), &owner, count, all).using_encoded(<T as system::Trait>::Hashing::hash);
+
+        ensure!(!<ParkingLots<T>>::exists(parking_lot_hash), "Parking lot hash collision");
+
+        let now = <timestamp::Module<T>>::get();
+
+        <ParkingLots<T>>::insert(parking_lot_hash, parking.clone());
+        <ParkingLotsByIndex<T>>::insert(all, parking_lot_hash);
+        <ParkingLotIndex<T>>::insert(parking_lot_hash, all);
+        <ParkingLotLastTime<T>>::insert(parking_lot_hash, now);
+        <LotsByCell<T>>::mutate(parking.location.cell(), |hashes| hashes.push(parking_lot_hash));
+        Self::add_owner_parking_lot(&owner, parking_lot_hash);
+        AllParkingLotsCount::put(all + 1);
+        Ok(())
+    }
+
+    fn _remove_parking_lot(owner: &T::AccountId, parking_lot_hash: T::Hash) {
+        // move the last parking lot into the hole so that indexes stay dense
+        let last = Self::all_parking_lots_count() - 1;
+        let index = <ParkingLotIndex<T>>::take(parking_lot_hash);
+        if index != last {
+            let last_hash = Self::parking_lots_by_index(last);
+            <ParkingLotsByIndex<T>>::insert(index, last_hash);
+            <ParkingLotIndex<T>>::insert(last_hash, index);
+        }
+        <ParkingLotsByIndex<T>>::remove(last);
+        AllParkingLotsCount::put(last);
+
+        Self::remove_owner_parking_lot(owner, parking_lot_hash);
+        if let Some(parking_lot) = <ParkingLots<T>>::take(parking_lot_hash) {
+            Self::remove_from_cell(parking_lot.location.cell(), parking_lot_hash);
+            for (category, _) in parking_lot.categories {
+                <CategoryFeeIndex<T>>::remove((parking_lot_hash, category));
+            }
+        }
+        <ParkingLotLastTime<T>>::remove(parking_lot_hash);
+        <ParkingLotFeeIndex<T>>::remove(parking_lot_hash);
+        <CurrentParkingCount<T>>::remove(parking_lot_hash);
+        <ParkingLotBookingRules<T>>::remove(parking_lot_hash);
+        <ParkingLotSpots<T>>::remove(parking_lot_hash);
+        <ParkingLotSchedule<T>>::remove(parking_lot_hash);
+        <ParkingLotFeeRules<T>>::remove(parking_lot_hash);
+        <ParkingLotBilling<T>>::remove(parking_lot_hash);
+        <ParkingLotLockedRate<T>>::remove(parking_lot_hash);
+        <ParkingLotOverstayRate<T>>::remove(parking_lot_hash);
+        <PassProducts<T>>::remove(parking_lot_hash);
+        <ParkingLotCommission<T>>::remove(parking_lot_hash);
+        <UserDailyFees<T>>::remove_prefix(&parking_lot_hash);
+        for code_hash in <ParkingLotCoupons<T>>::take(parking_lot_hash) {
+            <Coupons<T>>::remove(code_hash);
+        }
+    }
+
+    /// Enter the parking lot at the current time, with the price bounds of the user, the minutes paid up front
+    /// for a prepaid session and the promo code of a coupon
+    fn _entering(
+        user: T::AccountId,
+        parking_lot_hash: T::Hash,
+        category: SpotCategory,
+        guard: PriceGuard<BalanceOf<T>>,
+        prepaid_minutes: Option<u32>,
+        coupon: Option<Vec<u8>>,
+    ) -> Result {
+        ensure!(!<UserParkingInfo<T>>::exists(user.clone()), "User already has entered a parking lot");
+        ensure!(Self::user_debts(&user).is_empty(), "User has outstanding parking debt");
+
+        let info_hash = <system::Module<T>>::random_seed();
+        // record the entering time
+        let now = <timestamp::Module<T>>::get();
+
+        let mut parking_info = ParkingInfo::<T>::new(user.clone(), parking_lot_hash, info_hash, now);
+        let parking_lot = Self::parking_lots(parking_lot_hash).ok_or("The parking lot has not existed")?;
+        // a reservation or a booked window holds a position whatever the parking lot status is
+        let reservation = Self::reservations(&user);
+        let booking = Self::current_booking(&user, parking_lot_hash, now);
+        if reservation.is_some() || booking.is_some() {
+            ensure!(category == SpotCategory::Standard, "Reserved and booked positions are standard spots");
+        }
+        // a valid pass holds a standard position, it is not used with a reservation, a booking or a prepaid time
+        let pass = reservation.is_none()
+            && booking.is_none()
+            && prepaid_minutes.is_none()
+            && category == SpotCategory::Standard
+            && Self::user_passes((user.clone(), parking_lot_hash)).map_or(false, |pass| pass.expires > now);
+        match (&reservation, &booking) {
+            (Some(reservation), _) => {
+                ensure!(reservation.parking_lot_hash == parking_lot_hash, "User has reserved another parking lot");
+            }
+            (None, Some((_, booking))) => {
+                ensure!(booking.held || parking_lot.standard_remain() > 0, "The parking lot has no more position");
+            }
+            (None, None) if pass => {
+                ensure!(parking_lot.status == LotStatus::Open, "The parking lot is not open");
+            }
+            (None, None) => {
+                ensure!(parking_lot.status == LotStatus::Open, "The parking lot is not open");
+                if category == SpotCategory::Standard {
+                    // positions of the booked windows which have started are kept for their users
+                    ensure!(
+                        parking_lot.standard_remain() > Self::unheld_bookings(parking_lot_hash, now),
+                        "The parking lot has no more position"
+                    );
+                } else {
+                    let pool = parking_lot.pool(category).ok_or("The parking lot has no spots of the category")?;
+                    ensure!(pool.remain > 0, "The parking lot has no more position");
+                }
+            }
+        }
+
+        // a free spot is left for every position counted in `remain`
+        let spots = match Self::parking_lot_spots(parking_lot_hash) {
+            Some(mut spots) => {
+                let spot = spots.first_free(category).ok_or("The parking lot has no free spot")?;
+                spots.set_occupied(spot, true);
+                parking_info.spot = Some(spot);
+                Some(spots)
+            }
+            None => None,
+        };
+
+        // the price at inclusion with the user counted as parked, a booked session pays the booked price
+        let schedule = Self::parking_lot_schedule(parking_lot_hash);
+        let (price, inclusion_price) = match &booking {
+            Some((_, booking)) => (booking.price, booking.price),
+            None if pass => (Zero::zero(), Zero::zero()),
+            None => {
+                let mut parking_lot = parking_lot.clone();
+                if reservation.is_none() {
+                    parking_lot.occupy(category)?;
+                }
+                let price = parking_lot.category_price(category)?;
+                (price, scheduled_price::<T>(&schedule, price, now)?)
+            }
+        };
+        ensure!(
+            inclusion_price <= guard.max_price_per_second,
+            "The parking lot price is above the user's maximum price"
+        );
+        if let (Some(max_total), Some((_, booking))) = (guard.max_total, &booking) {
+            ensure!(booking.amount <= max_total, "The booked amount is above the user's maximum total");
+        }
+        let guarded = guard.action != GuardAction::Ignore;
+        ensure!(
+            !guarded || Self::guarded_sessions().len() < MAX_GUARDED_SESSIONS,
+            "Too many guarded sessions"
+        );
+        parking_info.price_guard = guard;
+
+        // a prepaid session pays the minutes up front at the price at inclusion
+        let prepaid = match prepaid_minutes {
+            Some(minutes) => {
+                ensure!(booking.is_none(), "User has a booked window in the parking lot");
+                let overstay_rate = Self::parking_lot_overstay_rate(parking_lot_hash)
+                    .ok_or("The parking lot does not take prepaid sessions")?;
+                let paid_until = prepaid_end::<T>(now, minutes)?;
+                Some((paid_until, overstay_rate, scheduled_fee::<T>(&schedule, price, now, paid_until)?))
+            }
+            None => None,
+        };
+        if booking.is_none() && prepaid.is_none() && !pass && Self::parking_lot_locked_rate(parking_lot_hash) {
+            parking_info.locked_price = Some(inclusion_price);
+        }
+        parking_info.pass = pass;
+        let coupon = match coupon {
+            Some(code) => Some(Self::usable_coupon(&code, &parking_lot, parking_lot_hash, now)?),
+            None => None,
+        };
+        parking_info.coupon = coupon.as_ref().map(|(code_hash, coupon)| (*code_hash, coupon.discount));
+
+        // the user starts paying from the current fee index of the category
+        let mut parking_lot = Self::accrue_fee_index(parking_lot, parking_lot_hash, now)?;
+        parking_info.category = category;
+        parking_info.fee_index = Self::stored_fee_index(parking_lot_hash, category);
+
+        // a reserved position or the position held for the booked window is already occupied,
+        // the user parks in one of them if both are
+        let booking_held = booking.as_ref().map_or(false, |(_, booking)| booking.held);
+        match (reservation.is_some(), booking_held) {
+            (true, true) => parking_lot.release(SpotCategory::Standard)?,
+            (false, false) if !pass => parking_lot.occupy(category)?,
+            _ => {}
+        }
+
+        let deposit = T::EnteringDeposit::get();
+        let prepaid_amount = prepaid.map_or_else(Zero::zero, |(_, _, amount)| amount);
+        let reserved = deposit.checked_add(&prepaid_amount).ok_or("Fee overflow")?;
+        T::Currency::reserve(&user, reserved).map_err(|_| "Not enough balance for the parking deposit")?;
+        parking_info.deposit = deposit;
+        if let Some(reservation) = reservation {
+            Self::remove_reservation(&user);
+            T::Currency::unreserve(&user, reservation.deposit);
+        }
+        if let Some((booking_id, booking)) = booking {
+            Self::remove_booking(booking_id, &booking);
+            let mut amount = booking.amount;
+            Self::collect_fee(&user, &parking_lot.owner, parking_lot_hash, booking.amount, &mut amount);
+            T::Currency::unreserve(&user, amount);
+            parking_info.base_fee = booking.amount;
+            parking_info.current_fee = booking.amount;
+            parking_info.paid_fee = booking.amount;
+            parking_info.booking = Some((booking.end, booking.price));
+        }
+        if let Some((paid_until, overstay_rate, amount)) = prepaid {
+            let mut reserved = amount;
+            Self::collect_fee(&user, &parking_lot.owner, parking_lot_hash, amount, &mut reserved);
+            T::Currency::unreserve(&user, reserved);
+            parking_info.base_fee = amount;
+            parking_info.current_fee = amount;
+            parking_info.paid_fee = amount;
+            parking_info.paid_until = Some(paid_until);
+            parking_info.overstay_rate = overstay_rate;
+        }
+
+        // change states
+        if let Some(spots) = spots {
+            <ParkingLotSpots<T>>::insert(parking_lot_hash, spots);
+        }
+        Self::add_parked_account(parking_lot_hash, &user);
+        <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+        <UserParkingInfo<T>>::insert(user.clone(), parking_info.clone());
+        if guarded {
+            <GuardedSessions<T>>::mutate(|users| users.push(user.clone()));
+        }
+        if let Some((paid_until, _, _)) = prepaid {
+            Self::add_prepaid_end(paid_until, &user);
+        }
+        if let Some((code_hash, coupon)) = coupon {
+            Self::use_coupon(code_hash, coupon);
+        }
+
+        Self::deposit_event(RawEvent::Entering(<timestamp::Module<T>>::get(), parking_info));
+        Ok(())
+    }
+
+    /// Leave the parking lot at `now` with the promo code of a coupon, pay the fee and refund the rest
+    /// of the deposit
+    fn _leaving(user: T::AccountId, now: T::Moment, coupon: Option<Vec<u8>>) -> Result {
+        let mut parking_info = Self::user_parking_info(user.clone()).ok_or("User has not entered a parking lot")?;
+        let parking_lot_hash = parking_info.parking_lot_hash.clone();
+        let mut parking_lot = Self::parking_lots(parking_lot_hash).expect("User must has the parking info. Qed");
+        let coupon = match coupon {
+            Some(code) => {
+                ensure!(parking_info.coupon.is_none(), "A coupon is already applied to the parking session");
+                let (code_hash, coupon) = Self::usable_coupon(&code, &parking_lot, parking_lot_hash, now)?;
+                parking_info.coupon = Some((code_hash, coupon.discount));
+                Some((code_hash, coupon))
+            }
+            None => None,
+        };
+        let owner = parking_lot.owner.clone();
+
+        // everything that can fail is computed before the first write, a failed call is not reverted
+        let (fee_index, category_fee_indexes) = Self::fee_indexes_at(&parking_lot, parking_lot_hash, now)?;
+        let session_fee_index =
+            Self::session_fee_index(parking_lot_hash, parking_info.category, fee_index, &category_fee_indexes);
+        Self::accrue_user_fee_to(&mut parking_info, session_fee_index, now)?;
+        let rules = Self::parking_lot_fee_rules(parking_lot_hash);
+        let (daily_fees, earlier_fees) = Self::recent_fees(&user, parking_lot_hash, now)?;
+        let (fee, limit) = Self::limit_fee(&parking_info, &rules, earlier_fees)?;
+        // the position of a valid pass is still held for it
+        if !parking_info.pass {
+            parking_lot.release(parking_info.category)?;
+        }
+
+        // change states
+        Self::store_fee_indexes(parking_lot_hash, now, fee_index, category_fee_indexes);
+        Self::pay_parking_fee(&user, &owner, &mut parking_info, fee, &rules, daily_fees);
+        if let (Some(spot), Some(mut spots)) = (parking_info.spot, Self::parking_lot_spots(parking_lot_hash)) {
+            spots.set_occupied(spot, false);
+            <ParkingLotSpots<T>>::insert(parking_lot_hash, spots);
+        }
+        Self::remove_parked_account(parking_lot_hash, &user);
+        <ParkingLots<T>>::insert(parking_lot_hash, parking_lot.clone());
+        <UserParkingInfo<T>>::remove(user.clone());
+        if parking_info.price_guard.action != GuardAction::Ignore {
+            <GuardedSessions<T>>::mutate(|users| users.retain(|guarded| *guarded != user));
+        }
+        if let Some(paid_until) = parking_info.paid_until {
+            Self::remove_prepaid_end(paid_until, &user);
+        }
+        if let Some((code_hash, coupon)) = coupon {
+            Self::use_coupon(code_hash, coupon);
+        }
+        Self::deposit_event(RawEvent::Leaving(now, user, owner, parking_info, limit));
+        Ok(())
+    }
+
+    /// Append a parking lot to the owner's array
+    fn add_owner_parking_lot(owner: &T::AccountId, parking_lot_hash: T::Hash) {
+        let count = Self::owner_parking_lots_count(owner);
+        <OwnerParkingLotsArray<T>>::insert((owner.clone(), count), parking_lot_hash);
+        <OwnerParkingLotIndex<T>>::insert(parking_lot_hash, count);
+        <OwnerParkingLotsCount<T>>::insert(owner, count + 1);
+    }
+
+    /// Swap remove a parking lot from the owner's array
+    fn remove_owner_parking_lot(owner: &T::AccountId, parking_lot_hash: T::Hash) {
+        let last = Self::owner_parking_lots_count(owner) - 1;
+        let index = <OwnerParkingLotIndex<T>>::take(parking_lot_hash);
+        if index != last {
+            let last_hash = Self::owner_parking_lots_array((owner.clone(), last));
+            <OwnerParkingLotsArray<T>>::insert((owner.clone(), index), last_hash);
+            <OwnerParkingLotIndex<T>>::insert(last_hash, index);
+        }
+        <OwnerParkingLotsArray<T>>::remove((owner.clone(), last));
+        <OwnerParkingLotsCount<T>>::insert(owner, last);
+    }
+
+
+    /// All users currently parked in a parking lot
+    pub fn current_parking_accounts(parking_lot_hash: T::Hash) -> Vec<T::AccountId> {
+        (0..Self::current_parking_count(parking_lot_hash))
+            .map(|index| Self::current_parking_account((parking_lot_hash, index)))
+            .collect()
+    }
+
+    /// Append a user to the parked users of a parking lot
+    fn add_parked_account(parking_lot_hash: T::Hash, user: &T::AccountId) {
+        let count = Self::current_parking_count(parking_lot_hash);
+        <CurrentParkingAccounts<T>>::insert((parking_lot_hash, count), user.clone());
+        <CurrentParkingIndex<T>>::insert(user, count);
+        <CurrentParkingCount<T>>::insert(parking_lot_hash, count + 1);
+    }
+
+    /// Swap remove a user from the parked users of a parking lot
+    fn remove_parked_account(parking_lot_hash: T::Hash, user: &T::AccountId) {
+        let last = Self::current_parking_count(parking_lot_hash) - 1;
+        let index = <CurrentParkingIndex<T>>::take(user);
+        if index != last {
+            let last_user = Self::current_parking_account((parking_lot_hash, last));
+            <CurrentParkingAccounts<T>>::insert((parking_lot_hash, index), last_user.clone());
+            <CurrentParkingIndex<T>>::insert(last_user, index);
+        }
+        <CurrentParkingAccounts<T>>::remove((parking_lot_hash, last));
+        <CurrentParkingCount<T>>::insert(parking_lot_hash, last);
+    }
+
+    /// Remove a parking lot from a cell of the location grid
+    fn remove_from_cell(cell: (i32, i32), parking_lot_hash: T::Hash) {
+        <LotsByCell<T>>::mutate(cell, |hashes| hashes.retain(|hash| *hash != parking_lot_hash));
+        if Self::lots_by_cell(cell).is_empty() {
+            <LotsByCell<T>>::remove(cell);
+        }
+    }
+
+    /// Pay parking fee when user leaving, the deposit settles the fee first and the rest of it is refunded.
+    /// The fee is already limited by the fee rules of the parking lot, `daily_fees` is the recent fees of the user
+    /// in the parking lot
+    fn pay_parking_fee(
+        user: &T::AccountId,
+        owner: &T::AccountId,
+        parking_info: &mut ParkingInfo<T>,
+        fee: BalanceOf<T>,
+        rules: &FeeRules<T::Moment, BalanceOf<T>>,
+        mut daily_fees: Vec<(T::Moment, BalanceOf<T>)>,
+    ) {
+        let parking_lot_hash = parking_info.parking_lot_hash;
+        // the part paid before leaving, e.g. the booked window, is not refunded
+        let full_fee = cmp::max(fee, parking_info.paid_fee);
+        let fee = cmp::max(fee - Self::coupon_discount(parking_info, fee), parking_info.paid_fee);
+        let unpaid_fee = fee - parking_info.paid_fee;
+        if let Some((code_hash, _)) = parking_info.coupon {
+            let event = RawEvent::CouponRedeemed(parking_info.current_time, user.clone(), code_hash, full_fee - fee);
+            Self::deposit_event(event);
+        }
+
+        Self::collect_fee(user, owner, parking_lot_hash, unpaid_fee, &mut parking_info.deposit);
+        parking_info.current_fee = fee;
+        parking_info.paid_fee = fee;
+        T::Currency::unreserve(user, parking_info.deposit);
+        parking_info.deposit = Zero::zero();
+
+        if rules.daily_cap.is_some() {
+            daily_fees.push((parking_info.current_time, fee));
+            <UserDailyFees<T>>::insert(&parking_lot_hash, user, &daily_fees);
+        } else {
+            <UserDailyFees<T>>::remove(&parking_lot_hash, user);
+        }
+    }
+
+    /// Fees of the sessions of the user in the parking lot which left during the 24 hours before `now`,
+    /// and their sum
+    fn recent_fees(
+        user: &T::AccountId,
+        parking_lot_hash: T::Hash,
+        now: T::Moment,
+    ) -> result::Result<(Vec<(T::Moment, BalanceOf<T>)>, BalanceOf<T>), &'static str> {
+        let window = to_moment::<T>(DAILY_CAP_WINDOW)?;
+        let mut fees = Self::user_daily_fees(&parking_lot_hash, user);
+        fees.retain(|(time, _)| time.checked_add(&window).map_or(true, |end| end > now));
+        let mut total: BalanceOf<T> = Zero::zero();
+        for (_, fee) in fees.iter() {
+            total = total.checked_add(fee).ok_or("Fee overflow")?;
+        }
+        Ok((fees, total))
+    }
+
+    /// Fee of the session under the fee rules of its parking lot and the rule that limited it,
+    /// `earlier_fees` is the fees of the other sessions in the parking lot during the last 24 hours
+    fn limit_fee(
+        parking_info: &ParkingInfo<T>,
+        rules: &FeeRules<T::Moment, BalanceOf<T>>,
+        earlier_fees: BalanceOf<T>,
+    ) -> result::Result<(BalanceOf<T>, FeeLimit), &'static str> {
+        // a prepaid session pays its prepaid time and the overstay whatever the fee rules are
+        if parking_info.paid_until.is_some() {
+            return Ok((parking_info.current_fee, FeeLimit::Unlimited));
+        }
+        let duration = parking_info
+            .current_time
+            .checked_sub(&parking_info.enter_time)
+            .ok_or("current time must greater than exiting time")?;
+        if !rules.grace_period.is_zero() && duration <= rules.grace_period {
+            return Ok((Zero::zero(), FeeLimit::GracePeriod));
+        }
+
+        let mut limited = (parking_info.current_fee, FeeLimit::Unlimited);
+        if let Some(cap) = rules.session_cap {
+            if cap < limited.0 {
+                limited = (cap, FeeLimit::SessionCap);
+            }
+        }
+        if let Some(cap) = rules.daily_cap {
+            let duration = TryInto::<u64>::try_into(duration).map_err(|_| "Time diff overflow")?;
+            let days = cmp::max((duration + DAILY_CAP_WINDOW - 1) / DAILY_CAP_WINDOW, 1);
+            let allowed = cap
+                .checked_mul(&to_balance::<T>(days.into())?)
+                .ok_or("Fee cap overflow")?
+                .checked_sub(&earlier_fees)
+                .unwrap_or_else(Zero::zero);
+            if allowed < limited.0 {
+                limited = (allowed, FeeLimit::DailyCap);
+            }
+        }
+        Ok(limited)
+    }
+
+    /// Pay the fees accrued until now by all parked users to the owner, limited by the fee rules of the parking lot
+    fn settle_accrued_fees(owner: &T::AccountId, accs: &[T::AccountId], now: T::Moment) {
+        for acc in accs {
+            let mut parking_info = match Self::user_parking_info(acc) {
+                Some(parking_info) => parking_info,
+                None => continue,
+            };
+            // the fee index is accrued by the caller, so it cannot go backwards here
+            if Self::accrue_user_fee(&mut parking_info, now).is_err() {
+                continue;
+            }
+            // only the fee allowed by the fee rules so far is paid, e.g. nothing within the grace period
+            let parking_lot_hash = parking_info.parking_lot_hash;
+            let rules = Self::parking_lot_fee_rules(parking_lot_hash);
+            let fee = match Self::recent_fees(acc, parking_lot_hash, now)
+                .and_then(|(_, earlier_fees)| Self::limit_fee(&parking_info, &rules, earlier_fees))
+            {
+                Ok((fee, _)) => fee - Self::coupon_discount(&parking_info, fee),
+                Err(_) => continue,
+            };
+            // a rounded up fee can be billed less later, the part paid is kept
+            let unpaid_fee = fee.checked_sub(&parking_info.paid_fee).unwrap_or_else(Zero::zero);
+            Self::collect_fee(acc, owner, parking_lot_hash, unpaid_fee, &mut parking_info.deposit);
+            parking_info.paid_fee += unpaid_fee;
+            <UserParkingInfo<T>>::insert(acc, parking_info);
+        }
+    }
+
+    /// Collect a fee for the owner of the parking lot from the user's parking deposit first and then from the free
+    /// balance, the commission of the parking lot goes to the treasury. Returns the part that the user cannot pay
+    fn collect_fee(
+        user: &T::AccountId,
+        owner: &T::AccountId,
+        parking_lot_hash: T::Hash,
+        fee: BalanceOf<T>,
+        deposit: &mut BalanceOf<T>,
+    ) -> BalanceOf<T> {
+        if user == owner || fee.is_zero() {
+            return Zero::zero();
+        }
+
+        let treasury = Self::treasury_account();
+        let mut commission = Self::commission(parking_lot_hash) * fee;
+        // a commission too small to open the treasury account stays with the owner
+        if T::Currency::total_balance(&treasury).saturating_add(commission) < T::Currency::minimum_balance() {
+            commission = Zero::zero();
+        }
+        let unpaid_commission = Self::pay_from_deposit(user, &treasury, commission, deposit);
+        unpaid_commission + Self::pay_from_deposit(user, owner, fee - commission, deposit)
+    }
+
+    /// Pay the amount to the creditor from the deposit first and then from the free balance,
+    /// returns the part that the user cannot pay and owes to the creditor
+    fn pay_from_deposit(
+        user: &T::AccountId,
+        creditor: &T::AccountId,
+        amount: BalanceOf<T>,
+        deposit: &mut BalanceOf<T>,
+    ) -> BalanceOf<T> {
+        if amount.is_zero() {
+            return Zero::zero();
+        }
+
+        let from_deposit = cmp::min(amount, *deposit);
+        // the deposit stays reserved if the creditor does not exist yet, e.g. the treasury before its first income
+        let rest = match T::Currency::repatriate_reserved(user, creditor, from_deposit) {
+            Ok(not_moved) => {
+                *deposit -= from_deposit;
+                amount - from_deposit + not_moved
+            }
+            Err(_) => amount,
+        };
+        if rest.is_zero() || T::Currency::transfer(user, creditor, rest).is_ok() {
+            return Zero::zero();
+        }
+
+        Self::add_debt(user, creditor, rest);
+        rest
+    }
+
+    /// Account of the treasury which receives the commission
+    pub fn treasury_account() -> T::AccountId {
+        MODULE_ID.into_account()
+    }
+
+    /// Commission of the parking lot
+    pub fn commission(parking_lot_hash: T::Hash) -> Permill {
+        Self::parking_lot_commission(parking_lot_hash)
+            .or_else(Self::platform_commission)
+            .unwrap_or_else(T::Commission::get)
+    }
+
+    /// Add the arriving deadline of the user's reservation
+    fn add_reservation_deadline(arrive_by: T::Moment, user: &T::AccountId) {
+        let bucket = timer_bucket::<T>(arrive_by);
+        <ReservationDeadlines<T>>::mutate(bucket, |deadlines| {
+            let position = deadlines
+                .iter()
+                .position(|(deadline, _)| *deadline > arrive_by)
+                .unwrap_or(deadlines.len());
+            deadlines.insert(position, (arrive_by, user.clone()));
+        });
+        if Self::reservation_deadline_cursor().map_or(true, |cursor| bucket < cursor) {
+            ReservationDeadlineCursor::put(bucket);
+        }
+    }
+
+    /// Remove the reservation of the user and its arriving deadline
+    fn remove_reservation(user: &T::AccountId) {
+        if let Some(reservation) = <Reservations<T>>::take(user) {
+            <ReservationDeadlines<T>>::mutate(timer_bucket::<T>(reservation.arrive_by), |deadlines| {
+                deadlines.retain(|(_, acc)| acc != user)
+            });
+        }
+    }
+
+    /// Expire the reservations whose arriving deadlines have passed
+    fn expire_reservations(now: T::Moment) {
+        let (first, last, cursor) = match due_buckets::<T>(Self::reservation_deadline_cursor(), now) {
+            Some(buckets) => buckets,
+            None => return,
+        };
+        for bucket in first..=last {
+            let deadlines = Self::reservation_deadlines(bucket);
+            let expired = deadlines.iter().take_while(|(arrive_by, _)| *arrive_by < now).count();
+            if expired == 0 {
+                continue;
+            }
+
+            if expired == deadlines.len() {
+                <ReservationDeadlines<T>>::remove(bucket);
+            } else {
+                <ReservationDeadlines<T>>::insert(bucket, deadlines[expired..].to_vec());
+            }
+            for (_, user) in deadlines[..expired].iter() {
+                Self::expire_reservation(user, now);
+            }
+        }
+        ReservationDeadlineCursor::put(cursor);
+    }
+
+    /// Release the reserved position and forfeit the reservation deposit to the owner,
+    /// the arriving deadline is removed by the caller
+    fn expire_reservation(user: &T::AccountId, now: T::Moment) {
+        let reservation = match <Reservations<T>>::take(user) {
+            Some(reservation) => reservation,
+            None => return,
+        };
+        let parking_lot_hash = reservation.parking_lot_hash;
+        // a parking lot cannot be removed while it has reservations
+        let parking_lot = Self::parking_lots(parking_lot_hash).expect("Reserved parking lot must be existed. Qed");
+        let owner = parking_lot.owner.clone();
+        if let Ok(mut parking_lot) = Self::accrue_fee_index(parking_lot, parking_lot_hash, now) {
+            if parking_lot.release(SpotCategory::Standard).is_ok() {
+                <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+            }
+        }
+
+        let mut deposit = reservation.deposit;
+        Self::collect_fee(user, &owner, parking_lot_hash, reservation.deposit, &mut deposit);
+        T::Currency::unreserve(user, deposit);
+        Self::deposit_event(RawEvent::ReservationExpired(now, user.clone(), parking_lot_hash, reservation.deposit));
+    }
+
+    /// Alert the users or make them leave when the price or the fee of their sessions exceeds their bounds,
+    /// a session is no longer watched once it has exceeded them
+    fn check_price_guards(now: T::Moment) {
+        let guarded = Self::guarded_sessions();
+        if guarded.is_empty() {
+            return;
+        }
+
+        let count = guarded.len();
+        let mut watched = Vec::with_capacity(count);
+        let mut exceeded = vec![];
+        for user in guarded {
+            let session = match Self::parking_session(&user, now) {
+                Some(session) => session,
+                None => continue,
+            };
+            // booked sessions keep their price whatever the occupancy is, locked ones whatever the schedule is too,
+            // and the stay is free while the pass is valid
+            let schedule = Self::parking_lot_schedule(session.parking_lot_hash);
+            let price = match (session.booking, session.locked_price) {
+                _ if session.pass => Ok(Zero::zero()),
+                (Some((_, price)), _) => scheduled_price::<T>(&schedule, price, now),
+                (None, Some(price)) => Ok(price),
+                (None, None) => Self::parking_lots(session.parking_lot_hash)
+                    .ok_or("The parking lot has not existed")
+                    .and_then(|parking_lot| parking_lot.category_price(session.category))
+                    .and_then(|price| scheduled_price::<T>(&schedule, price, now)),
+            };
+            let guard = session.price_guard;
+            match price {
+                Ok(price)
+                    if price > guard.max_price_per_second
+                        || guard.max_total.map_or(false, |max_total| session.current_fee > max_total) =>
+                {
+                    exceeded.push((user, session, price))
+                }
+                _ => watched.push(user),
+            }
+        }
+        // the users who have left are dropped as well as the exceeded sessions
+        if watched.len() == count {
+            return;
+        }
+
+        <GuardedSessions<T>>::put(watched);
+        for (user, session, price) in exceeded {
+            let action = session.price_guard.action;
+            Self::deposit_event(RawEvent::PriceGuardTriggered(
+                now,
+                user.clone(),
+                session.parking_lot_hash,
+                price,
+                session.current_fee,
+                action,
+            ));
+            // the session is kept unwatched if it cannot leave now, nothing is written by a failed leaving
+            if action == GuardAction::Leave && Self::_leaving(user.clone(), now, None).is_err() {
+                <UserParkingInfo<T>>::mutate(&user, |parking_info| {
+                    if let Some(parking_info) = parking_info {
+                        parking_info.price_guard.action = GuardAction::Ignore;
+                    }
+                });
+            }
+        }
+    }
+
+    /// The coupon of the promo code if it can be applied to a session in the parking lot at `now`,
+    /// and the hash of the promo code
+    fn usable_coupon(
+        code: &[u8],
+        parking_lot: &ParkingLot<T>,
+        parking_lot_hash: T::Hash,
+        now: T::Moment,
+    ) -> result::Result<(T::Hash, Coupon<T>), &'static str> {
+        ensure!(code.len() <= MAX_COUPON_CODE_LENGTH, "The promo code is too long");
+        let code_hash = <T as system::Trait>::Hashing::hash(code);
+        let coupon = Self::coupons(code_hash).ok_or("The coupon has not existed")?;
+        ensure!(
+            coupon.owner == parking_lot.owner && coupon.parking_lot_hash.map_or(true, |hash| hash == parking_lot_hash),
+            "The coupon is not for the parking lot"
+        );
+        ensure!(now < coupon.expires, "The coupon has expired");
+        ensure!(coupon.uses_left > 0, "The coupon has been used up");
+        Ok((code_hash, coupon))
+    }
+
+    /// Take a use of the coupon applied to a session
+    fn use_coupon(code_hash: T::Hash, mut coupon: Coupon<T>) {
+        coupon.uses_left -= 1;
+        <Coupons<T>>::insert(code_hash, coupon);
+    }
+
+    /// The part of the fee taken off by the coupon applied to the session
+    fn coupon_discount(parking_info: &ParkingInfo<T>, fee: BalanceOf<T>) -> BalanceOf<T> {
+        parking_info.coupon.map_or_else(Zero::zero, |(_, discount)| discount.amount(fee))
+    }
+
+    /// Add the expiry time of the user's pass for the parking lot
+    fn add_pass_expiry(expires: T::Moment, user: &T::AccountId, parking_lot_hash: T::Hash) {
+        let bucket = timer_bucket::<T>(expires);
+        <PassExpiries<T>>::mutate(bucket, |expiries| {
+            let position = expiries.iter().position(|(time, _, _)| *time > expires).unwrap_or(expiries.len());
+            expiries.insert(position, (expires, user.clone(), parking_lot_hash));
+        });
+        if Self::pass_expiry_cursor().map_or(true, |cursor| bucket < cursor) {
+            PassExpiryCursor::put(bucket);
+        }
+    }
+
+    /// Renew or expire the passes whose expiry times have passed
+    fn expire_passes(now: T::Moment) {
+        let (first, last, cursor) = match due_buckets::<T>(Self::pass_expiry_cursor(), now) {
+            Some(buckets) => buckets,
+            None => return,
+        };
+        // a renewed pass expires after `now`, so it is never due again in this walk
+        PassExpiryCursor::put(cursor);
+        for bucket in first..=last {
+            let expiries = Self::pass_expiries(bucket);
+            let expired = expiries.iter().take_while(|(expires, _, _)| *expires <= now).count();
+            if expired == 0 {
+                continue;
+            }
+
+            if expired == expiries.len() {
+                <PassExpiries<T>>::remove(bucket);
+            } else {
+                <PassExpiries<T>>::insert(bucket, expiries[expired..].to_vec());
+            }
+            for (_, user, parking_lot_hash) in expiries[..expired].iter() {
+                if !Self::renew_pass(user, *parking_lot_hash, now) {
+                    Self::expire_pass(user, *parking_lot_hash, now);
+                }
+            }
+        }
+    }
+
+    /// Charge the user the current price of the product and extend the pass by its duration,
+    /// returns whether the pass was renewed
+    fn renew_pass(user: &T::AccountId, parking_lot_hash: T::Hash, now: T::Moment) -> bool {
+        let mut pass = match Self::user_passes((user.clone(), parking_lot_hash)) {
+            Some(pass) => pass,
+            None => return false,
+        };
+        if !pass.auto_renew {
+            return false;
+        }
+        let products = Self::pass_products(parking_lot_hash);
+        let pass_product = match products.get(pass.product as usize) {
+            // a product whose limit was lowered renews only the passes within the limit
+            Some(pass_product) if pass_product.sold <= pass_product.limit => pass_product,
+            _ => return false,
+        };
+        let expires = match pass.expires.checked_add(&pass_product.duration) {
+            Some(expires) if expires > now => expires,
+            _ => return false,
+        };
+        if !Self::user_debts(user).is_empty() || T::Currency::reserve(user, pass_product.price).is_err() {
+            return false;
+        }
+        // a parking lot cannot be removed while it has passes
+        let parking_lot = Self::parking_lots(parking_lot_hash).expect("Parking lot of a pass must be existed. Qed");
+
+        let mut reserved = pass_product.price;
+        Self::collect_fee(user, &parking_lot.owner, parking_lot_hash, pass_product.price, &mut reserved);
+        T::Currency::unreserve(user, reserved);
+        pass.expires = expires;
+        <UserPasses<T>>::insert((user.clone(), parking_lot_hash), pass);
+        Self::add_pass_expiry(expires, user, parking_lot_hash);
+        Self::deposit_event(RawEvent::PassRenewed(now, user.clone(), parking_lot_hash, expires));
+        true
+    }
+
+    /// Remove the pass and free its position, a user parked with the pass stays parked and pays
+    /// from now on
+    fn expire_pass(user: &T::AccountId, parking_lot_hash: T::Hash, now: T::Moment) {
+        let pass = match <UserPasses<T>>::take((user.clone(), parking_lot_hash)) {
+            Some(pass) => pass,
+            None => return,
+        };
+        <PassProducts<T>>::mutate(parking_lot_hash, |products| {
+            if let Some(pass_product) = products.get_mut(pass.product as usize) {
+                pass_product.sold = pass_product.sold.saturating_sub(1);
+            }
+        });
+
+        let parking_lot = Self::parking_lots(parking_lot_hash).expect("Parking lot of a pass must be existed. Qed");
+        if let Ok(mut parking_lot) = Self::accrue_fee_index(parking_lot, parking_lot_hash, now) {
+            let parked = Self::user_parking_info(user)
+                .filter(|parking_info| parking_info.pass && parking_info.parking_lot_hash == parking_lot_hash);
+            if let Some(mut parking_info) = parked {
+                // the position is the one the user parks in now
+                parking_info.pass = false;
+                parking_info.pass_ended = Some(now);
+                parking_info.fee_index = Self::stored_fee_index(parking_lot_hash, SpotCategory::Standard);
+                parking_info.current_time = now;
+                <UserParkingInfo<T>>::insert(user, parking_info);
+            } else if parking_lot.release(SpotCategory::Standard).is_ok() {
+                <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+            }
+        }
+        Self::deposit_event(RawEvent::PassExpired(now, user.clone(), parking_lot_hash));
+    }
+
+    /// Add the end of the prepaid time of the user's session
+    fn add_prepaid_end(paid_until: T::Moment, user: &T::AccountId) {
+        let bucket = timer_bucket::<T>(paid_until);
+        <PrepaidEnds<T>>::mutate(bucket, |ends| {
+            let position = ends.iter().position(|(end, _)| *end > paid_until).unwrap_or(ends.len());
+            ends.insert(position, (paid_until, user.clone()));
+        });
+        if Self::prepaid_end_cursor().map_or(true, |cursor| bucket < cursor) {
+            PrepaidEndCursor::put(bucket);
+        }
+    }
+
+    /// Remove the end of the prepaid time of the user's session
+    fn remove_prepaid_end(paid_until: T::Moment, user: &T::AccountId) {
+        <PrepaidEnds<T>>::mutate(timer_bucket::<T>(paid_until), |ends| ends.retain(|(_, acc)| acc != user));
+    }
+
+    /// Deposit an event for each prepaid session whose prepaid time has ended, the users are still parked
+    /// and pay the overstay when leaving
+    fn expire_prepaid(now: T::Moment) {
+        let (first, last, cursor) = match due_buckets::<T>(Self::prepaid_end_cursor(), now) {
+            Some(buckets) => buckets,
+            None => return,
+        };
+        for bucket in first..=last {
+            let ends = Self::prepaid_ends(bucket);
+            let expired = ends.iter().take_while(|(end, _)| *end <= now).count();
+            if expired == 0 {
+                continue;
+            }
+
+            for (_, user) in ends[..expired].iter() {
+                if let Some(parking_info) = Self::user_parking_info(user) {
+                    Self::deposit_event(RawEvent::Expired(now, user.clone(), parking_info.parking_lot_hash));
+                }
+            }
+            if expired == ends.len() {
+                <PrepaidEnds<T>>::remove(bucket);
+            } else {
+                <PrepaidEnds<T>>::insert(bucket, ends[expired..].to_vec());
+            }
+        }
+        PrepaidEndCursor::put(cursor);
+    }
+
+    /// Largest number of the bookings overlapping at any moment of the window from `start` until `end`
+    fn peak_bookings(bookings: &[(T::Moment, T::Moment, T::Hash)], start: T::Moment, end: T::Moment) -> u32 {
+        // +1 where an overlapping booking starts and -1 where it ends, windows do not include their ends
+        // so ends are counted before starts at the same moment
+        let mut changes = bookings
+            .iter()
+            .filter(|(booked_start, booked_end, _)| *booked_start < end && *booked_end > start)
+            .flat_map(|(booked_start, booked_end, _)| {
+                vec![(cmp::max(*booked_start, start), 1i32), (cmp::min(*booked_end, end), -1i32)]
+            })
+            .collect::<Vec<_>>();
+        changes.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        let mut current = 0i32;
+        let mut peak = 0i32;
+        for (_, change) in changes {
+            current += change;
+            peak = cmp::max(peak, current);
+        }
+        peak as u32
+    }
+
+    /// Number of the bookings of the parking lot whose windows include `now` but whose positions are not held yet,
+    /// i.e. the windows have started since the positions were held last time
+    fn unheld_bookings(parking_lot_hash: T::Hash, now: T::Moment) -> u32 {
+        let held_until = Self::bookings_held_until();
+        Self::parking_lot_bookings(parking_lot_hash)
+            .iter()
+            .filter(|(start, end, _)| held_until < *start && *start <= now && now < *end)
+            .count() as u32
+    }
+
+    /// Number of the passes of the parking lot which hold a position
+    fn sold_passes(parking_lot_hash: T::Hash) -> u32 {
+        Self::pass_products(parking_lot_hash).iter().map(|pass_product| pass_product.sold).sum()
+    }
+
+    /// The booking of the user whose window of the parking lot includes `now`
+    fn current_booking(
+        user: &T::AccountId,
+        parking_lot_hash: T::Hash,
+        now: T::Moment,
+    ) -> Option<(T::Hash, Booking<T>)> {
+        Self::user_bookings(user)
+            .into_iter()
+            .filter_map(|booking_id| Self::bookings(booking_id).map(|booking| (booking_id, booking)))
+            .find(|(_, booking)| {
+                booking.parking_lot_hash == parking_lot_hash && booking.start <= now && now < booking.end
+            })
+    }
+
+    /// Remove the booking from all indexes
+    fn remove_booking(booking_id: T::Hash, booking: &Booking<T>) {
+        <Bookings<T>>::remove(booking_id);
+        <ParkingLotBookings<T>>::mutate(booking.parking_lot_hash, |bookings| {
+            bookings.retain(|(_, _, id)| *id != booking_id)
+        });
+        <UserBookings<T>>::mutate(&booking.user, |ids| ids.retain(|id| *id != booking_id));
+        <BookingStarts<T>>::mutate(timer_bucket::<T>(booking.start), |starts| {
+            starts.retain(|(_, id)| *id != booking_id)
+        });
+        <BookingEnds<T>>::mutate(timer_bucket::<T>(booking.end), |ends| ends.retain(|(_, id)| *id != booking_id));
+    }
+
+    /// Add the start of the booked window
+    fn add_booking_start(start: T::Moment, booking_id: T::Hash) {
+        let bucket = timer_bucket::<T>(start);
+        <BookingStarts<T>>::mutate(bucket, |starts| {
+            let position = starts.iter().position(|(time, _)| *time > start).unwrap_or(starts.len());
+            starts.insert(position, (start, booking_id));
+        });
+        if Self::booking_start_cursor().map_or(true, |cursor| bucket < cursor) {
+            BookingStartCursor::put(bucket);
+        }
+    }
+
+    /// Hold a position for each booked window which has started, the positions of the windows which have not
+    /// are kept from walk-ins by `unheld_bookings`
+    fn hold_bookings(now: T::Moment) {
+        let (first, last, cursor) = match due_buckets::<T>(Self::booking_start_cursor(), now) {
+            Some(buckets) => buckets,
+            None => return,
+        };
+        // a walk behind the time holds the windows until the end of its last bucket
+        let held_until = if cursor == last {
+            now
+        } else {
+            to_moment::<T>((last + 1) * TIMER_BUCKET - 1).unwrap_or(now)
+        };
+        for bucket in first..=last {
+            let starts = Self::booking_starts(bucket);
+            let due = starts.iter().take_while(|(start, _)| *start <= now).count();
+            if due == 0 {
+                continue;
+            }
+
+            if due == starts.len() {
+                <BookingStarts<T>>::remove(bucket);
+            } else {
+                <BookingStarts<T>>::insert(bucket, starts[due..].to_vec());
+            }
+            for (_, booking_id) in starts[..due].iter() {
+                Self::hold_booking(*booking_id, now);
+            }
+        }
+        BookingStartCursor::put(cursor);
+        <BookingsHeldUntil<T>>::put(held_until);
+    }
+
+    /// Occupy a standard position for the booked window, the booking is refunded if no position is free
+    fn hold_booking(booking_id: T::Hash, now: T::Moment) {
+        let mut booking = match Self::bookings(booking_id) {
+            Some(booking) => booking,
+            None => return,
+        };
+        let parking_lot_hash = booking.parking_lot_hash;
+        // a parking lot cannot be removed while it has bookings
+        let parking_lot = Self::parking_lots(parking_lot_hash).expect("Booked parking lot must be existed. Qed");
+        if parking_lot.standard_remain() > 0 {
+            if let Ok(mut parking_lot) = Self::accrue_fee_index(parking_lot, parking_lot_hash, now) {
+                if parking_lot.occupy(SpotCategory::Standard).is_ok() {
+                    <ParkingLots<T>>::insert(parking_lot_hash, parking_lot);
+                    booking.held = true;
+                    <Bookings<T>>::insert(booking_id, booking);
+                    return;
+                }
+            }
+        }
+
+        // walk-ins have taken the positions, the position was never provided so nothing is forfeited
+        Self::remove_booking(booking_id, &booking);
+        T::Currency::unreserve(&booking.user, booking.amount);
+        Self::deposit_event(RawEvent::BookingRefunded(now, booking_id, booking.amount));
+    }
+
+    /// Release the position held for the booked window
+    fn release_booking_hold(booking: &Booking<T>, now: T::Moment) {
+        if !booking.held {
+            return;
+        }
+        let parking_lot =
+            Self::parking_lots(booking.parking_lot_hash).expect("Booked parking lot must be existed. Qed");
+        if let Ok(mut parking_lot) = Self::accrue_fee_index(parking_lot, booking.parking_lot_hash, now) {
+            if parking_lot.release(SpotCategory::Standard).is_ok() {
+                <ParkingLots<T>>::insert(booking.parking_lot_hash, parking_lot);
+            }
+        }
+    }
+
+    /// Add the end of the booked window
+    fn add_booking_end(end: T::Moment, booking_id: T::Hash) {
+        let bucket = timer_bucket::<T>(end);
+        <BookingEnds<T>>::mutate(bucket, |ends| {
+            let position = ends.iter().position(|(time, _)| *time > end).unwrap_or(ends.len());
+            ends.insert(position, (end, booking_id));
+        });
+        if Self::booking_end_cursor().map_or(true, |cursor| bucket < cursor) {
+            BookingEndCursor::put(bucket);
+        }
+    }
+
+    /// Forfeit the amount of the booked windows which have ended without the user entering to the owner,
+    /// and release their held positions
+    fn expire_bookings(now: T::Moment) {
+        let (first, last, cursor) = match due_buckets::<T>(Self::booking_end_cursor(), now) {
+            Some(buckets) => buckets,
+            None => return,
+        };
+        for bucket in first..=last {
+            let ends = Self::booking_ends(bucket);
+            let expired = ends.iter().take_while(|(end, _)| *end <= now).count();
+            if expired == 0 {
+                continue;
+            }
+
+            for (_, booking_id) in ends[..expired].iter() {
+                let booking = match Self::bookings(booking_id) {
+                    Some(booking) => booking,
+                    None => continue,
+                };
+                let parking_lot =
+                    Self::parking_lots(booking.parking_lot_hash).expect("Booked parking lot must be existed. Qed");
+                Self::remove_booking(*booking_id, &booking);
+                Self::release_booking_hold(&booking, now);
+                let mut amount = booking.amount;
+                Self::collect_fee(
+                    &booking.user,
+                    &parking_lot.owner,
+                    booking.parking_lot_hash,
+                    booking.amount,
+                    &mut amount,
+                );
+                T::Currency::unreserve(&booking.user, amount);
+                Self::deposit_event(RawEvent::BookingForfeited(now, *booking_id, booking.amount));
+            }
+            if expired == ends.len() {
+                <BookingEnds<T>>::remove(bucket);
+            } else {
+                <BookingEnds<T>>::insert(bucket, ends[expired..].to_vec());
+            }
+        }
+        BookingEndCursor::put(cursor);
+    }
+
+    /// Record the balance the user owes to the owner
+    fn add_debt(user: &T::AccountId, owner: &T::AccountId, amount: BalanceOf<T>) {
+        <UserDebts<T>>::mutate(user, |debts| match debts.iter_mut().find(|(creditor, _)| creditor == owner) {
+            Some((_, debt)) => *debt += amount,
+            None => debts.push((owner.clone(), amount)),
+        });
+        Self::deposit_event(RawEvent::DebtCreated(<timestamp::Module<T>>::get(), user.clone(), owner.clone(), amount));
+    }
+
+    /// Accrue the fee index of the parking lot until `now` with the price of its current occupancy.
+    /// Only the parking lot is touched, parked users catch up with the index when they need their fee.
+    fn accrue_fee_index(
+        parking_lot: ParkingLot<T>,
+        parking_lot_hash: T::Hash,
+        now: T::Moment,
+    ) -> result::Result<ParkingLot<T>, &'static str> {
+        let (fee_index, category_fee_indexes) = Self::fee_indexes_at(&parking_lot, parking_lot_hash, now)?;
+        Self::store_fee_indexes(parking_lot_hash, now, fee_index, category_fee_indexes);
+        Ok(parking_lot)
+    }
+
+    /// Store the fee indexes of the parking lot accrued until `now`
+    fn store_fee_indexes(
+        parking_lot_hash: T::Hash,
+        now: T::Moment,
+        fee_index: u128,
+        category_fee_indexes: Vec<(SpotCategory, u128)>,
+    ) {
+        <ParkingLotLastTime<T>>::insert(parking_lot_hash, now);
+        <ParkingLotFeeIndex<T>>::insert(parking_lot_hash, fee_index);
+        for (category, fee_index) in category_fee_indexes {
+            <CategoryFeeIndex<T>>::insert((parking_lot_hash, category), fee_index);
+        }
+    }
+
+    /// Fee index of the positions of the category among the fee indexes computed by `fee_indexes_at`
+    fn session_fee_index(
+        parking_lot_hash: T::Hash,
+        category: SpotCategory,
+        fee_index: u128,
+        category_fee_indexes: &[(SpotCategory, u128)],
+    ) -> u128 {
+        match category {
+            SpotCategory::Standard => fee_index,
+            category => category_fee_indexes
+                .iter()
+                .find(|(index_category, _)| *index_category == category)
+                .map_or_else(|| Self::category_fee_index((parking_lot_hash, category)), |(_, fee_index)| *fee_index),
+        }
+    }
+
+    /// Fee indexes of the standard positions and every category of the parking lot at `now`,
+    /// computed from the stored ones without writing them
+    fn fee_indexes_at(
+        parking_lot: &ParkingLot<T>,
+        parking_lot_hash: T::Hash,
+        now: T::Moment,
+    ) -> result::Result<(u128, Vec<(SpotCategory, u128)>), &'static str> {
+        let mut fee_index = Self::parking_lot_fee_index(parking_lot_hash);
+        let mut category_fee_indexes = Vec::with_capacity(parking_lot.categories.len());
+        // no one in parking lot, no need to accrue fees
+        if parking_lot.remain == parking_lot.capacity {
+            return Ok((fee_index, category_fee_indexes));
+        }
+
+        let old_time = Self::parking_lot_last_time(parking_lot_hash).expect("It must be having data. Qed");
+        let schedule = Self::parking_lot_schedule(parking_lot_hash);
+        if parking_lot.standard_remain() < parking_lot.standard_capacity() {
+            let (new_fee, _) = parking_lot.compute_new_fee(now, old_time, &schedule)?;
+            fee_index = fee_index.checked_add(new_fee).ok_or("Fee index overflow")?;
+        }
+        for (category, pool) in parking_lot.categories.iter() {
+            if pool.remain == pool.capacity {
+                continue;
+            }
+            let price = pool.compute_price::<T::PricingModel>(&parking_lot.pricing)?;
+            let new_fee = scheduled_fee_millis::<T>(&schedule, price, old_time, now)?;
+            let category_fee_index = Self::category_fee_index((parking_lot_hash, *category))
+                .checked_add(new_fee)
+                .ok_or("Fee index overflow")?;
+            category_fee_indexes.push((*category, category_fee_index));
+        }
+        Ok((fee_index, category_fee_indexes))
+    }
+
+    /// Stored fee index of the positions of the category
+    fn stored_fee_index(parking_lot_hash: T::Hash, category: SpotCategory) -> u128 {
+        match category {
+            SpotCategory::Standard => Self::parking_lot_fee_index(parking_lot_hash),
+            _ => Self::category_fee_index((parking_lot_hash, category)),
+        }
+    }
+
+    /// Add the fee accrued since the last time to the parking info,
+    /// the fee index of its parking lot must have been accrued until `now`
+    fn accrue_user_fee(parking_info: &mut ParkingInfo<T>, now: T::Moment) -> Result {
+        let fee_index = Self::stored_fee_index(parking_info.parking_lot_hash, parking_info.category);
+        Self::accrue_user_fee_to(parking_info, fee_index, now)
+    }
+
+    /// Add the fee accrued since the last time to the parking info with the given fee index of its parking lot,
+    /// and bill the metered fee with the billing of the parking lot
+    fn accrue_user_fee_to(parking_info: &mut ParkingInfo<T>, fee_index: u128, now: T::Moment) -> Result {
+        let new_fee = match (parking_info.booking, parking_info.paid_until, parking_info.locked_price) {
+            // the stay is free while the pass is valid, the fee index is taken when it expires
+            _ if parking_info.pass => 0,
+            // the booked window is paid at entering, only the stay after it is charged
+            (Some((end, price)), _, _) => {
+                let from = cmp::max(end, parking_info.current_time);
+                if now > from {
+                    let schedule = Self::parking_lot_schedule(parking_info.parking_lot_hash);
+                    scheduled_fee_millis::<T>(&schedule, price, from, now)?
+                } else {
+                    0
+                }
+            }
+            // the prepaid time is paid up front, the stay after it is charged at the overstay rate
+            (None, Some(paid_until), _) => {
+                let from = cmp::max(paid_until, parking_info.current_time);
+                if now > from {
+                    fee_millis::<T>(parking_info.overstay_rate, from, now)?
+                } else {
+                    0
+                }
+            }
+            // a locked price is charged flat since the last time instead of the increase of the fee index
+            (None, None, Some(price)) => fee_millis::<T>(price, parking_info.current_time, now)?,
+            (None, None, None) => {
+                let new_fee = fee_index
+                    .checked_sub(parking_info.fee_index)
+                    .ok_or("Fee index must be increasing")?;
+                parking_info.fee_index = fee_index;
+                new_fee
+            }
+        };
+        parking_info.metered_fee = parking_info.metered_fee.checked_add(new_fee).ok_or("Fee overflow")?;
+        parking_info.current_time = now;
+        let billing = Self::parking_lot_billing(parking_info.parking_lot_hash);
+        parking_info.current_fee = Self::billed_fee(parking_info, &billing)?;
+        Ok(())
+    }
+
+    /// The fee of the session with the metered fee scaled from the metered time to the billed time,
+    /// the part of the balance left is rounded down only here
+    fn billed_fee(parking_info: &ParkingInfo<T>, billing: &Billing) -> result::Result<BalanceOf<T>, &'static str> {
+        // the booked window and the prepaid time are paid at entering and the pass is paid when bought,
+        // only the time after them is metered
+        let metered_from = match (parking_info.booking, parking_info.paid_until, parking_info.pass_ended) {
+            (Some((end, _)), _, _) => cmp::max(end, parking_info.enter_time),
+            (None, Some(paid_until), _) => paid_until,
+            (None, None, Some(pass_ended)) => pass_ended,
+            (None, None, None) => parking_info.enter_time,
+        };
+        let metered_time = match parking_info.current_time.checked_sub(&metered_from) {
+            Some(time) => TryInto::<u64>::try_into(time).map_err(|_| "Time diff overflow")?,
+            None => 0,
+        };
+        // scaled by the quotient and the remainder so that a long session at a large price cannot overflow
+        let fee = if metered_time == 0 {
+            0
+        } else {
+            let billed_time = u128::from(billing.billed_millis(metered_time));
+            let metered_time = u128::from(metered_time);
+            let remainder = parking_info.metered_fee % metered_time;
+            (parking_info.metered_fee / metered_time)
+                .checked_mul(billed_time)
+                .and_then(|fee| fee.checked_add(remainder * billed_time / metered_time))
+                .ok_or("Fee overflow")?
+        };
+        parking_info
+            .base_fee
+            .checked_add(&to_balance::<T>(fee / MILLIS_PER_SECOND)?)
+            .ok_or("Fee overflow")
+    }
+}
+
+// Queries for the runtime api, they never write the storage
+impl<T: Trait> Module<T> {
+    /// Parking lots in the order of `ParkingLotsByIndex` starting from `offset`, at most `limit` of them
+    pub fn parking_lots_page(offset: u64, limit: u64) -> Vec<(T::Hash, ParkingLot<T>)> {
+        let end = cmp::min(offset.saturating_add(limit), Self::all_parking_lots_count());
+        (offset..end)
+            .map(|index| Self::parking_lots_by_index(index))
+            .filter_map(|hash| Self::parking_lots(hash).map(|parking_lot| (hash, parking_lot)))
+            .collect()
+    }
+
+    /// All parking lots of the owner
+    pub fn owner_parking_lots(owner: &T::AccountId) -> Vec<(T::Hash, ParkingLot<T>)> {
+        (0..Self::owner_parking_lots_count(owner))
+            .map(|index| Self::owner_parking_lots_array((owner.clone(), index)))
+            .filter_map(|hash| Self::parking_lots(hash).map(|parking_lot| (hash, parking_lot)))
+            .collect()
+    }
+
+    /// Parking info of the user with the fee accrued until `now` and limited by the fee rules,
+    /// a time before the last update of the parking lot is taken as the last update
+    pub fn parking_session(user: &T::AccountId, now: T::Moment) -> Option<ParkingInfo<T>> {
+        let mut parking_info = Self::user_parking_info(user)?;
+        let parking_lot_hash = parking_info.parking_lot_hash;
+        let parking_lot = Self::parking_lots(parking_lot_hash)?;
+        let now = cmp::max(now, Self::parking_lot_last_time(parking_lot_hash)?);
+
+        let (fee_index, category_fee_indexes) = Self::fee_indexes_at(&parking_lot, parking_lot_hash, now).ok()?;
+        let fee_index =
+            Self::session_fee_index(parking_lot_hash, parking_info.category, fee_index, &category_fee_indexes);
+        Self::accrue_user_fee_to(&mut parking_info, fee_index, now).ok()?;
+
+        // the fee the user would pay when leaving now
+        let rules = Self::parking_lot_fee_rules(parking_lot_hash);
+        let (_, earlier_fees) = Self::recent_fees(user, parking_lot_hash, now).ok()?;
+        let (fee, _) = Self::limit_fee(&parking_info, &rules, earlier_fees).ok()?;
+        let fee = fee - Self::coupon_discount(&parking_info, fee);
+        parking_info.current_fee = cmp::max(fee, parking_info.paid_fee);
+        Some(parking_info)
+    }
+
+    /// Parking lots within `radius` meters of the center sorted by the distance, at most `limit` of them.
+    /// Only parking lots with at least `min_remain` positions and a current price not above `max_price` are found,
+    /// the radius is at most `MAX_NEAR_RADIUS`
+    pub fn lots_near(
+        center: GeoPoint,
+        radius: u32,
+        limit: u32,
+        min_remain: u32,
+        max_price: Option<BalanceOf<T>>,
+    ) -> Vec<(T::Hash, ParkingLot<T>, u64)> {
+        let radius = cmp::min(radius, MAX_NEAR_RADIUS);
+        let mut lots = geo::BoundingBox::around(&center, radius)
+            .cells()
+            .into_iter()
+            .take(MAX_NEAR_CELLS)
+            .flat_map(|cell| Self::lots_by_cell(cell))
+            .filter_map(|hash| Self::parking_lots(hash).map(|parking_lot| (hash, parking_lot)))
+            .filter(|(_, parking_lot)| {
+                parking_lot.remain >= min_remain && max_price.map_or(true, |price| parking_lot.current_price <= price)
+            })
+            .map(|(hash, parking_lot)| {
+                let distance = center.distance(&parking_lot.location);
+                (hash, parking_lot, distance)
+            })
+            .filter(|(_, _, distance)| *distance <= u64::from(radius))
+            .collect::<Vec<_>>();
+        lots.sort_by_key(|(_, _, distance)| *distance);
+        lots.truncate(limit as usize);
+        lots
+    }
+
+    /// Fee of parking `seconds` in a position of the category if entering now and the occupancy does not change,
+    /// charged as leaving would with the price schedule, the billing and the fee rules of the parking lot.
+    /// Nothing if the positions of the category are full
+    pub fn quote_fee(parking_lot_hash: T::Hash, category: SpotCategory, seconds: u64) -> Option<BalanceOf<T>> {
+        let mut parking_lot = Self::parking_lots(parking_lot_hash)?;
+        let remain = match category {
+            SpotCategory::Standard => parking_lot.standard_remain(),
+            category => parking_lot.pool(category)?.remain,
+        };
+        if remain == 0 {
+            return None;
+        }
+
+        // the price after entering, with the user counted as parked
+        parking_lot.occupy(category).ok()?;
+        let price = parking_lot.category_price(category).ok()?;
+        let now = <timestamp::Module<T>>::get();
+        let end = now.checked_add(&to_moment::<T>(seconds.checked_mul(1000)?).ok()?)?;
+        let schedule = Self::parking_lot_schedule(parking_lot_hash);
+
+        // the session a user entering now would have when leaving at the end, charged as leaving charges it
+        let mut parking_info = ParkingInfo::<T>::new(Default::default(), parking_lot_hash, Default::default(), now);
+        parking_info.category = category;
+        parking_info.metered_fee = if Self::parking_lot_locked_rate(parking_lot_hash) {
+            fee_millis::<T>(scheduled_price::<T>(&schedule, price, now).ok()?, now, end).ok()?
+        } else {
+            scheduled_fee_millis::<T>(&schedule, price, now, end).ok()?
+        };
+        parking_info.current_time = end;
+        parking_info.current_fee = Self::billed_fee(&parking_info, &Self::parking_lot_billing(parking_lot_hash)).ok()?;
+        let rules = Self::parking_lot_fee_rules(parking_lot_hash);
+        Self::limit_fee(&parking_info, &rules, Zero::zero()).ok().map(|(fee, _)| fee)
+    }
+}
+
+/// tests for this module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use primitives::{Blake2Hasher, H256};
+    use runtime_io::{with_externalities, TestExternalities};
+    use sr_primitives::weights::Weight;
+    use sr_primitives::Perbill;
+    use sr_primitives::{
+        testing::Header,
+        traits::{BlakeTwo256, Hash, IdentityLookup, OnFinalize, OnInitialize},
+    };
+    use support::{assert_err, assert_ok, impl_outer_origin, parameter_types};
+
+    impl_outer_origin! {
+        pub enum Origin for Test {}
+    }
+
+    // For testing the module, we construct most of a mock runtime. This means
+    // first constructing a configuration type (`Test`) which `impl`s each of the
+    // configuration traits of modules we want to use.
+    #[derive(Clone, Eq, PartialEq)]
+    pub struct Test;
+
+    parameter_types! {
+        pub const BlockHashCount: u64 = 250;
+        pub const MaximumBlockWeight: Weight = 1024;
+        pub const MaximumBlockLength: u32 = 2 * 1024;
+        pub const AvailableBlockRatio: Perbill = Perbill::from_percent(75);
+    }
+
+    impl system::Trait for Test {
+        type Origin = Origin;
+        type Call = ();
+        type Index = u64;
+        type BlockNumber = u64;
+        type Hash = H256;
+        type Hashing = BlakeTwo256;
+        type AccountId = u64;
+        type Lookup = IdentityLookup<Self::AccountId>;
+        type Header = Header;
+        type WeightMultiplierUpdate = ();
+        type Event = ();
+        type BlockHashCount = BlockHashCount;
+        type MaximumBlockWeight = MaximumBlockWeight;
+        type MaximumBlockLength = MaximumBlockLength;
+        type AvailableBlockRatio = AvailableBlockRatio;
+        type Version = ();
+    }
+
+    parameter_types! {
+        pub const ExistentialDeposit: u128 = 0;
+        pub const TransferFee: u128 = 0;
+        pub const CreationFee: u128 = 0;
+        pub const TransactionBaseFee: u128 = 0;
+        pub const TransactionByteFee: u128 = 0;
+    }
+
+    impl balances::Trait for Test {
+        // the balance type of the runtime
+        type Balance = u128;
+        type OnFreeBalanceZero = ();
+        type OnNewAccount = ();
+        type Event = ();
+        type TransactionPayment = ();
+        type TransferPayment = ();
+        type DustRemoval = ();
+        type ExistentialDeposit = ExistentialDeposit;
+        type TransferFee = TransferFee;
+        type CreationFee = CreationFee;
+        type TransactionBaseFee = TransactionBaseFee;
+        type TransactionByteFee = TransactionByteFee;
+        type WeightToFee = ();
+    }
+
+    parameter_types! {
+        pub const MinimumPeriod: u64 = 1000;
+    }
+
+    impl timestamp::Trait for Test {
+        type Moment = u64;
+        type OnTimestampSet = ();
+        type MinimumPeriod = MinimumPeriod;
+    }
+
+    parameter_types! {
+        pub const EnteringDeposit: u128 = 100;
+        pub const ReservationDeposit: u128 = 50;
+        pub const MaxReservationPeriod: u64 = 3_600_000;
+        pub const Commission: Permill = Permill::from_percent(0);
+    }
+
+    impl Trait for Test {
+        type Event = ();
+        type Currency = balances::Module<Test>;
+        type PricingModel = DefaultPricing;
+        type EnteringDeposit = EnteringDeposit;
+        type ReservationDeposit = ReservationDeposit;
+        type MaxReservationPeriod = MaxReservationPeriod;
+        type Commission = Commission;
+    }
+
+    type Parking = Module<Test>;
+
+    // This function basically just builds a genesis storage key/value store according to
+    // our desired mockup.
+    fn new_test_ext() -> TestExternalities<Blake2Hasher> {
+        let mut t = system::GenesisConfig::default().build_storage::<Test>().unwrap();
+        GenesisConfig::<Test>::default().assimilate_storage(&mut t).unwrap();
+        // or
+        // t.0.extend(GenesisConfig::<Test>::default().build_storage().unwrap().0);
+
+        balances::GenesisConfig::<Test> {
+            balances: vec![
+                (0, 1000),
+                (1, 1000),
+                (2, 1000),
+                (3, 1000),
+                (4, 150),
+                (5, 50),
+                (6, 1_000_000_000_000_000_000_000_000_000),
+            ],
+            vesting: vec![],
+        }
+        .assimilate_storage(&mut t)
+        .unwrap();
+
+        GenesisConfig::<Test> {
+            parking_lots: vec![(0, 10, 10, 10, 10, 100, 60, 60), (1, 100, 1, 100, 100, 100, 61, 61)],
+        }
+        .assimilate_storage(&mut t)
+        .unwrap();
+        t.into()
+    }
+
+    // Entering without bounds on the price
+    fn enter(user: u64, parking_lot_hash: H256, category: SpotCategory) -> Result {
+        Parking::entering(
+            Origin::signed(user),
+            parking_lot_hash,
+            category,
+            u128::max_value(),
+            None,
+            GuardAction::Ignore,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_new_parking_lot() {
+        with_externalities(&mut new_test_ext(), || {
+            let user = 0;
+            assert_eq!(Parking::all_parking_lots_count(), 2);
+            assert_ok!(Parking::new_parking_lot(Origin::signed(user), 50, 50, 100, 50, 100,));
+            assert_eq!(Parking::all_parking_lots_count(), 3);
+            assert_eq!(Parking::owner_parking_lots_count(user), 2);
+            assert_eq!(Parking::owner_parking_lots_count(1), 1);
+
+            assert_err!(
+                Parking::new_parking_lot(Origin::signed(user), 90_000_001, 50, 100, 50, 100),
+                "Location is out of range"
+            );
+            assert_err!(
+                Parking::new_parking_lot(Origin::signed(user), 50, -180_000_001, 100, 50, 100),
+                "Location is out of range"
+            );
+            assert_eq!(Parking::all_parking_lots_count(), 3);
+        })
+    }
+
+    #[test]
+    fn test_entering_and_leving() {
+        with_externalities(&mut new_test_ext(), || {
+            let user = 1;
+            assert_eq!(Parking::all_parking_lots_count(), 2);
+
+            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
+            assert_eq!(Parking::parking_lots_by_index(0), parking_lot_hash);
+            assert_ok!(enter(user, parking_lot_hash.clone(), SpotCategory::Standard));
+
+            let parking_info = Parking::user_parking_info(user).unwrap();
+            assert_eq!(parking_info.user_id, user);
+            assert_eq!(parking_info.current_fee, 0);
+            assert_eq!(parking_info.current_time.clone(), parking_info.enter_time.clone());
+            assert_err!(
+                enter(user, parking_lot_hash.clone(), SpotCategory::Standard),
+                "User already has entered a parking lot"
+            );
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+
+            assert_ok!(enter(user, parking_lot_hash.clone(), SpotCategory::Standard));
+            let parking_info = Parking::user_parking_info(user).unwrap();
+            assert_eq!(parking_info.user_id, user);
+            assert_eq!(parking_info.current_fee, 0);
+            assert_eq!(parking_info.current_time.clone(), parking_info.enter_time.clone());
+            assert_err!(
+                enter(user, parking_lot_hash.clone(), SpotCategory::Standard),
+                "User already has entered a parking lot"
+            );
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+        })
+    }
+
+    #[test]
+    fn test_update_parking_lot() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(Parking::update_parking_lot(
+                Origin::signed(owner),
+                parking_lot_hash,
+                70,
+                71,
+                20,
+                20,
+                200
+            ));
+
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(
+                parking_lot.location,
+                GeoPoint {
+                    latitude: 70,
+                    longitude: 71
+                }
+            );
+            assert_eq!(parking_lot.capacity, 20);
+            assert_eq!(parking_lot.remain, 20);
+            assert_eq!(parking_lot.min_price, 20);
+            assert_eq!(parking_lot.max_price, 200);
+            assert_eq!(parking_lot.current_price, 20);
+
+            assert_err!(
+                Parking::update_parking_lot(Origin::signed(1), parking_lot_hash, 70, 71, 20, 20, 200),
+                "Only the owner can update the parking lot"
+            );
+            assert_err!(
+                Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, 70, 71, 20, 200, 20),
+                "min price must equal and less than max price"
+            );
+            assert_err!(
+                Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, -90_000_001, 71, 20, 20, 200),
+                "Location is out of range"
+            );
+        })
+    }
+
+    #[test]
+    fn test_update_parking_lot_with_parked_users() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let user = 1;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+
+            assert_err!(
+                Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, 60, 60, 1, 10, 100),
+                "Capacity cannot be less than the number of parked users"
+            );
+
+            // fees of the elapsed 10s are settled with the old price curve: 2 * 90 / 10 + 10
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_ok!(Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, 60, 60, 2, 50, 50));
+            assert_eq!(Parking::parking_lot_fee_index(parking_lot_hash), 280_000);
+
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.remain, 0);
+            assert_eq!(parking_lot.current_price, 50);
+            assert_eq!(Parking::parking_lot_last_time(parking_lot_hash), Some(10_000));
+
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+            assert_eq!(balances::Module::<Test>::free_balance(user), 720);
+        })
+    }
+
+    #[test]
+    fn test_parking_lot_status() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let user = 1;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::set_parking_lot_status(Origin::signed(user), parking_lot_hash, LotStatus::Closed),
+                "Only the owner can change the parking lot status"
+            );
+
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            assert_ok!(Parking::set_parking_lot_status(Origin::signed(owner), parking_lot_hash, LotStatus::Closed));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().status, LotStatus::Closed);
+            assert_err!(enter(2, parking_lot_hash, SpotCategory::Standard), "The parking lot is not open");
+            // parked users can still leave a closed parking lot
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+
+            assert_ok!(Parking::set_parking_lot_status(
+                Origin::signed(owner),
+                parking_lot_hash,
+                LotStatus::Maintenance
+            ));
+            assert_err!(enter(user, parking_lot_hash, SpotCategory::Standard), "The parking lot is not open");
+
+            assert_ok!(Parking::set_parking_lot_status(Origin::signed(owner), parking_lot_hash, LotStatus::Open));
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+        })
+    }
+
+    #[test]
+    fn test_remove_parking_lot() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let user = 1;
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 50, 50, 100, 50, 100));
+            let first = Parking::owner_parking_lots_array((owner, 0));
+            let second = Parking::owner_parking_lots_array((owner, 1));
+            let other = Parking::owner_parking_lots_array((1, 0));
+
+            assert_ok!(enter(user, first, SpotCategory::Standard));
+            assert_err!(
+                Parking::remove_parking_lot(Origin::signed(user), first),
+                "Only the owner can remove the parking lot"
+            );
+            assert_err!(
+                Parking::remove_parking_lot(Origin::signed(owner), first),
+                "The parking lot still has parked users"
+            );
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+            assert_ok!(Parking::remove_parking_lot(Origin::signed(owner), first));
+
+            assert!(Parking::parking_lots(first).is_none());
+            assert_eq!(Parking::parking_lot_last_time(first), None);
+            assert!(!<CurrentParkingCount<Test>>::exists(first));
+
+            // the last parking lot fills the removed index
+            assert_eq!(Parking::all_parking_lots_count(), 2);
+            assert_eq!(Parking::parking_lots_by_index(0), second);
+            assert_eq!(Parking::parking_lot_index(second), 0);
+            assert_eq!(Parking::parking_lots_by_index(1), other);
+            assert!(!<ParkingLotsByIndex<Test>>::exists(2));
+
+            assert_eq!(Parking::owner_parking_lots_count(owner), 1);
+            assert_eq!(Parking::owner_parking_lots_array((owner, 0)), second);
+            assert_eq!(Parking::owner_parking_lot_index(second), 0);
+            assert!(!<OwnerParkingLotsArray<Test>>::exists((owner, 1)));
+        })
+    }
+
+    #[test]
+    fn test_transfer_parking_lot() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let new_owner = 2;
+            let user = 1;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::transfer_parking_lot(Origin::signed(user), parking_lot_hash, new_owner),
+                "Only the owner can transfer the parking lot"
+            );
+            assert_err!(
+                Parking::transfer_parking_lot(Origin::signed(owner), parking_lot_hash, owner),
+                "The parking lot already belongs to the account"
+            );
+
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_ok!(Parking::transfer_parking_lot(Origin::signed(owner), parking_lot_hash, new_owner));
+
+            // the fee of the first 10s (1 * 90 / 10 + 10 per second) goes to the old owner
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1190);
+            assert_eq!(balances::Module::<Test>::free_balance(user), 810);
+            assert_eq!(Parking::user_parking_info(user).unwrap().paid_fee, 190);
+
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().owner, new_owner);
+            assert_eq!(Parking::owner_parking_lots_count(owner), 0);
+            assert!(!<OwnerParkingLotsArray<Test>>::exists((owner, 0)));
+            assert_eq!(Parking::owner_parking_lots_count(new_owner), 1);
+            assert_eq!(Parking::owner_parking_lots_array((new_owner, 0)), parking_lot_hash);
+
+            // the rest of the fee goes to the new owner
+            timestamp::Module::<Test>::set_timestamp(20_000);
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1190);
+            assert_eq!(balances::Module::<Test>::free_balance(new_owner), 1190);
+            assert_eq!(balances::Module::<Test>::free_balance(user), 620);
+        })
+    }
+
+    #[test]
+    fn test_current_parking_accounts() {
+        with_externalities(&mut new_test_ext(), || {
+            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
+            for user in 1..4 {
+                assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            }
+            assert_eq!(Parking::current_parking_accounts(parking_lot_hash), vec![1, 2, 3]);
+
+            // the last parked user fills the index of the leaving user
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(Parking::current_parking_count(parking_lot_hash), 2);
+            assert_eq!(Parking::current_parking_accounts(parking_lot_hash), vec![3, 2]);
+            assert_eq!(Parking::current_parking_index(3), 0);
+            assert!(!<CurrentParkingIndex<Test>>::exists(1));
+            assert!(!<CurrentParkingAccounts<Test>>::exists((parking_lot_hash, 2)));
+        })
+    }
+
+    fn price(curve: &PriceCurve<u128>, occupied: u32) -> result::Result<u128, &'static str> {
+        <DefaultPricing as PricingModel<u128>>::price(curve, 10, 100, 10, occupied)
+    }
+
+    #[test]
+    fn test_linear_pricing() {
+        with_externalities(&mut new_test_ext(), || {
+            assert_eq!(price(&PriceCurve::Linear, 0), Ok(10));
+            assert_eq!(price(&PriceCurve::Linear, 5), Ok(55));
+            assert_eq!(price(&PriceCurve::Linear, 10), Ok(100));
+            assert_eq!(price(&PriceCurve::Linear, 11), Err("Remained num greater than capacity"));
+
+            // linear is the default price curve
+            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().pricing, PriceCurve::Linear);
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.compute_current_price(), Ok(28));
+        })
+    }
+
+    #[test]
+    fn test_flat_pricing() {
+        with_externalities(&mut new_test_ext(), || {
+            assert_eq!(price(&PriceCurve::Flat, 0), Ok(10));
+            assert_eq!(price(&PriceCurve::Flat, 10), Ok(10));
+
+            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
+            assert_err!(
+                Parking::set_pricing(Origin::signed(1), parking_lot_hash, PriceCurve::Flat),
+                "Only the owner can change the parking lot pricing"
+            );
+            assert_ok!(Parking::set_pricing(Origin::signed(0), parking_lot_hash, PriceCurve::Flat));
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(0), 1100);
+        })
+    }
+
+    #[test]
+    fn test_stepped_pricing() {
+        with_externalities(&mut new_test_ext(), || {
+            let stepped = PriceCurve::Stepped(vec![(50, 40), (80, 90)]);
+            assert_eq!(price(&stepped, 0), Ok(10));
+            assert_eq!(price(&stepped, 4), Ok(10));
+            assert_eq!(price(&stepped, 5), Ok(40));
+            assert_eq!(price(&stepped, 7), Ok(40));
+            assert_eq!(price(&stepped, 8), Ok(90));
+            assert_eq!(price(&stepped, 10), Ok(90));
+
+            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
+            assert_err!(
+                Parking::set_pricing(Origin::signed(0), parking_lot_hash, PriceCurve::Stepped(vec![])),
+                "Stepped price needs at least one tier"
+            );
+            assert_err!(
+                Parking::set_pricing(
+                    Origin::signed(0),
+                    parking_lot_hash,
+                    PriceCurve::Stepped(vec![(80, 40), (50, 90)])
+                ),
+                "Tier thresholds must be increasing"
+            );
+            assert_err!(
+                Parking::set_pricing(Origin::signed(0), parking_lot_hash, PriceCurve::Stepped(vec![(50, 5)])),
+                "Tier price must be between min price and max price"
+            );
+
+            assert_ok!(Parking::set_pricing(
+                Origin::signed(0),
+                parking_lot_hash,
+                PriceCurve::Stepped(vec![(10, 40)])
+            ));
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.compute_current_price(), Ok(40));
+        })
+    }
+
+    #[test]
+    fn test_surge_pricing() {
+        with_externalities(&mut new_test_ext(), || {
+            let surge = PriceCurve::Surge {
+                threshold: 50,
+                growth: 50,
+            };
+            assert_eq!(price(&surge, 0), Ok(10));
+            assert_eq!(price(&surge, 5), Ok(10));
+            assert_eq!(price(&surge, 6), Ok(15));
+            assert_eq!(price(&surge, 7), Ok(22));
+            assert_eq!(price(&surge, 8), Ok(33));
+            assert_eq!(price(&surge, 9), Ok(49));
+            assert_eq!(price(&surge, 10), Ok(73));
+            let steep = PriceCurve::Surge {
+                threshold: 0,
+                growth: 1000,
+            };
+            assert_eq!(price(&steep, 2), Ok(100));
+
+            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
+            assert_err!(
+                Parking::set_pricing(
+                    Origin::signed(0),
+                    parking_lot_hash,
+                    PriceCurve::Surge {
+                        threshold: 50,
+                        growth: 0
+                    }
+                ),
+                "Surge growth must be positive"
+            );
+            assert_ok!(Parking::set_pricing(Origin::signed(0), parking_lot_hash, steep));
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.compute_current_price(), Ok(100));
+        })
+    }
+
+    #[test]
+    fn test_fee_index() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().current_price, 19);
+
+            // 10s with one user: 1 * 90 / 10 + 10 per second
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::parking_lot_fee_index(parking_lot_hash), 190_000);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().current_price, 28);
+            // other parked users are not touched
+            let parking_info = Parking::user_parking_info(1).unwrap();
+            assert_eq!(parking_info.current_fee, 0);
+            assert_eq!(parking_info.fee_index, 0);
+            assert_eq!(Parking::user_parking_info(2).unwrap().fee_index, 190_000);
+
+            // 10s with two users: 2 * 90 / 10 + 10 per second
+            timestamp::Module::<Test>::set_timestamp(20_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(Parking::parking_lot_fee_index(parking_lot_hash), 470_000);
+            assert_eq!(balances::Module::<Test>::free_balance(1), 530);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().current_price, 19);
+
+            // 10s with one user again
+            timestamp::Module::<Test>::set_timestamp(30_000);
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+            assert_eq!(Parking::parking_lot_fee_index(parking_lot_hash), 660_000);
+            assert_eq!(balances::Module::<Test>::free_balance(2), 530);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1940);
+        })
+    }
+
+    #[test]
+    fn test_spot_categories() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::set_spot_category(Origin::signed(1), parking_lot_hash, SpotCategory::EvCharging, 2, 20, 40),
+                "Only the owner can change the parking lot categories"
+            );
+            assert_err!(
+                Parking::set_spot_category(Origin::signed(owner), parking_lot_hash, SpotCategory::Standard, 2, 20, 40),
+                "Standard spots are the positions not in any category"
+            );
+            assert_ok!(Parking::set_spot_category(
+                Origin::signed(owner),
+                parking_lot_hash,
+                SpotCategory::EvCharging,
+                2,
+                20,
+                40
+            ));
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.capacity, 10);
+            assert_eq!(parking_lot.standard_capacity(), 8);
+
+            // the positions of a category are priced with their own range
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::EvCharging));
+            assert_eq!(Parking::user_parking_info(1).unwrap().category, SpotCategory::EvCharging);
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.remain, 9);
+            assert_eq!(parking_lot.current_price, 10);
+            assert_eq!(parking_lot.pool(SpotCategory::EvCharging).unwrap().current_price, 30);
+            assert_err!(
+                enter(3, parking_lot_hash, SpotCategory::Compact),
+                "The parking lot has no spots of the category"
+            );
+
+            // 1 * 90 / 8 + 10 per second for the standard positions
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().current_price, 21);
+
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 700);
+            assert_eq!(balances::Module::<Test>::free_balance(2), 790);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1510);
+
+            // a category cannot shrink below its parked users or take more than the standard positions
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::EvCharging));
+            assert_err!(
+                Parking::set_spot_category(
+                    Origin::signed(owner),
+                    parking_lot_hash,
+                    SpotCategory::EvCharging,
+                    0,
+                    20,
+                    40
+                ),
+                "Capacity cannot be less than the number of parked users"
+            );
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_err!(
+                Parking::set_spot_category(Origin::signed(owner), parking_lot_hash, SpotCategory::Compact, 11, 5, 5),
+                "Not enough standard spots for the category"
+            );
+            assert_ok!(Parking::set_spot_category(
+                Origin::signed(owner),
+                parking_lot_hash,
+                SpotCategory::EvCharging,
+                0,
+                20,
+                40
+            ));
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert!(parking_lot.categories.is_empty());
+            assert_eq!(parking_lot.standard_capacity(), 10);
+        })
+    }
+
+    #[test]
+    fn test_spot_map() {
+        let mut spots = SpotMap::new(70);
+        for spot in 0..64 {
+            spots.set_occupied(spot, true);
+        }
+        assert_eq!(spots.first_free(SpotCategory::Standard), Some(64));
+        spots.set_out_of_service(64, true);
+        assert_eq!(spots.first_free(SpotCategory::Standard), Some(65));
+        assert_eq!(spots.out_of_service_count(), 1);
+
+        assert_eq!(spots.resize(64), Err("Spots to remove are still in use"));
+        assert_ok!(spots.resize(65));
+        assert_eq!(spots.first_free(SpotCategory::Standard), None);
+        spots.set_occupied(3, false);
+        assert_eq!(spots.first_free(SpotCategory::Standard), Some(3));
+        assert_ok!(spots.resize(128));
+        assert_eq!(spots.occupied.len(), 2);
+        assert!(!spots.is_occupied(100));
+
+        // the spots of a category are only assigned to its users
+        let mut spots = SpotMap::new(10);
+        assert_ok!(spots.assign_category(SpotCategory::EvCharging, 2));
+        assert_eq!(spots.category(1), SpotCategory::EvCharging);
+        assert_eq!(spots.category_count(SpotCategory::Standard), 8);
+        assert_eq!(spots.first_free(SpotCategory::Standard), Some(2));
+        assert_eq!(spots.first_free(SpotCategory::EvCharging), Some(0));
+        assert_eq!(spots.first_free(SpotCategory::Compact), None);
+        spots.set_occupied(0, true);
+        assert_ok!(spots.assign_category(SpotCategory::EvCharging, 1));
+        assert_eq!(spots.category(1), SpotCategory::Standard);
+        assert_eq!(spots.first_free(SpotCategory::EvCharging), None);
+        assert_eq!(
+            spots.assign_category(SpotCategory::Compact, 10),
+            Err("Not enough free spots for the category")
+        );
+        assert_ok!(spots.assign_category(SpotCategory::EvCharging, 0));
+        assert!(spots.categories.iter().all(|(category, _)| *category != SpotCategory::EvCharging));
+    }
+
+    #[test]
+    fn test_spot_numbering_categories() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(Parking::set_spot_category(
+                Origin::signed(owner),
+                parking_lot_hash,
+                SpotCategory::EvCharging,
+                2,
+                20,
+                40
+            ));
+            assert_ok!(Parking::set_spot_numbering(Origin::signed(owner), parking_lot_hash, true));
+            let spots = Parking::parking_lot_spots(parking_lot_hash).unwrap();
+            assert_eq!(spots.category(0), SpotCategory::EvCharging);
+            assert_eq!(spots.category(2), SpotCategory::Standard);
+
+            // users are given the spots of their category
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::EvCharging));
+            assert_eq!(Parking::user_parking_info(1).unwrap().spot, Some(2));
+            assert_eq!(Parking::user_parking_info(2).unwrap().spot, Some(0));
+            assert_err!(
+                Parking::set_spot_in_service(Origin::signed(owner), parking_lot_hash, 1, false),
+                "Only standard spots can be taken out of service"
+            );
+
+            // a larger category takes the lowest free standard spots
+            assert_ok!(Parking::set_spot_category(
+                Origin::signed(owner),
+                parking_lot_hash,
+                SpotCategory::EvCharging,
+                3,
+                20,
+                40
+            ));
+            let spots = Parking::parking_lot_spots(parking_lot_hash).unwrap();
+            assert_eq!(spots.category_count(SpotCategory::EvCharging), 3);
+            assert_eq!(spots.category(3), SpotCategory::EvCharging);
+            assert_ok!(enter(3, parking_lot_hash, SpotCategory::EvCharging));
+            assert_eq!(Parking::user_parking_info(3).unwrap().spot, Some(1));
+        })
+    }
+
+    #[test]
+    fn test_spot_numbering() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::set_spot_in_service(Origin::signed(owner), parking_lot_hash, 0, false),
+                "The spots of the parking lot are not numbered"
+            );
+            assert_err!(
+                Parking::set_spot_numbering(Origin::signed(1), parking_lot_hash, true),
+                "Only the owner can change the parking lot spots"
+            );
+            assert_ok!(Parking::set_spot_numbering(Origin::signed(owner), parking_lot_hash, true));
+            assert_eq!(Parking::parking_lot_spots(parking_lot_hash).unwrap().count, 10);
+
+            // users get the lowest free spot
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::user_parking_info(1).unwrap().spot, Some(0));
+            assert_eq!(Parking::user_parking_info(2).unwrap().spot, Some(1));
+            assert_err!(
+                Parking::set_spot_in_service(Origin::signed(owner), parking_lot_hash, 1, false),
+                "The spot is occupied"
+            );
+            assert_err!(
+                Parking::set_spot_numbering(Origin::signed(owner), parking_lot_hash, false),
+                "The parking lot still has parked users"
+            );
+
+            // a spot out of service is not a position of the parking lot
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert!(!Parking::parking_lot_spots(parking_lot_hash).unwrap().is_occupied(0));
+            assert_ok!(Parking::set_spot_in_service(Origin::signed(owner), parking_lot_hash, 0, false));
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.capacity, 9);
+            assert_eq!(parking_lot.remain, 8);
+            assert_ok!(enter(3, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::user_parking_info(3).unwrap().spot, Some(2));
+
+            assert_ok!(Parking::set_spot_in_service(Origin::signed(owner), parking_lot_hash, 0, true));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().capacity, 10);
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::user_parking_info(1).unwrap().spot, Some(0));
+
+            // spots cannot be removed while they are out of service
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+            assert_ok!(Parking::leaving(Origin::signed(3), None));
+            assert_ok!(Parking::set_spot_in_service(Origin::signed(owner), parking_lot_hash, 9, false));
+            assert_err!(
+                Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, 60, 60, 5, 10, 100),
+                "Spots to remove are still in use"
+            );
+            assert_ok!(Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, 60, 60, 12, 10, 100));
+            assert_eq!(Parking::parking_lot_spots(parking_lot_hash).unwrap().count, 13);
+
+            // the spots out of service are given back when the numbering stops
+            assert_ok!(Parking::set_spot_numbering(Origin::signed(owner), parking_lot_hash, false));
+            assert!(Parking::parking_lot_spots(parking_lot_hash).is_none());
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().capacity, 13);
+        })
+    }
+
+    #[test]
+    fn test_price_schedule() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            // the unix epoch is Thursday 00:00, the 4320th minute of the week
+            let schedule = vec![
+                ScheduleEntry {
+                    start: 4320,
+                    end: 4321,
+                    rule: PriceRule::Percent(50),
+                },
+                ScheduleEntry {
+                    start: 4321,
+                    end: 4322,
+                    rule: PriceRule::Fixed(1),
+                },
+            ];
+            assert_err!(
+                Parking::set_price_schedule(Origin::signed(1), parking_lot_hash, schedule.clone()),
+                "Only the owner can change the parking lot schedule"
+            );
+            assert_err!(
+                Parking::set_price_schedule(Origin::signed(owner), parking_lot_hash, vec![
+                    schedule[1].clone(),
+                    schedule[0].clone()
+                ]),
+                "Schedule entries must be sorted and not overlap"
+            );
+            assert_err!(
+                Parking::set_price_schedule(Origin::signed(owner), parking_lot_hash, vec![ScheduleEntry {
+                    start: 0,
+                    end: MINUTES_PER_WEEK + 1,
+                    rule: PriceRule::Fixed(1),
+                }]),
+                "Schedule entry must be within the week"
+            );
+            assert_ok!(Parking::set_price_schedule(Origin::signed(owner), parking_lot_hash, schedule.clone()));
+            assert_eq!(Parking::parking_lot_schedule(parking_lot_hash), schedule);
+
+            // 60s at half of 19, 30s at 1
+            assert_eq!(Parking::quote_fee(parking_lot_hash, SpotCategory::Standard, 90), Some(570));
+
+            // the stay is split at both boundaries: 60s at 9, 60s at 1 and 10s at 19
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(130_000);
+            assert_eq!(Parking::parking_session(&1, 130_000).unwrap().current_fee, 790);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 210);
+
+            // the schedule repeats every week, Sunday 23:59 to Monday 00:02 with double price on Monday 00:00
+            let monday = 4 * 24 * 3_600_000;
+            let schedule = vec![ScheduleEntry {
+                start: 0,
+                end: 1,
+                rule: PriceRule::Percent(200),
+            }];
+            assert_eq!(scheduled_fee::<Test>(&schedule, 10, monday - 60_000, monday + 120_000), Ok(2400));
+            assert_eq!(
+                scheduled_fee::<Test>(&schedule, 10, monday + 7 * 24 * 3_600_000, monday + 7 * 24 * 3_600_000 + 90_000),
+                Ok(1500)
+            );
+
+            assert_ok!(Parking::set_price_schedule(Origin::signed(owner), parking_lot_hash, vec![]));
+            assert!(!<ParkingLotSchedule<Test>>::exists(parking_lot_hash));
+        })
+    }
+
+    #[test]
+    fn test_fee_rules() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            let rules = FeeRules {
+                grace_period: 60_000,
+                session_cap: Some(300),
+                daily_cap: Some(500),
+            };
+            assert_err!(
+                Parking::set_fee_rules(Origin::signed(1), parking_lot_hash, rules),
+                "Only the owner can change the parking lot fee rules"
+            );
+            assert_ok!(Parking::set_fee_rules(Origin::signed(owner), parking_lot_hash, rules));
+
+            // 30s at 19 is free within the grace period
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(30_000);
+            assert_eq!(Parking::parking_session(&1, 30_000).unwrap().current_fee, 0);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 1000);
+
+            // 70s at 19 is capped by the session cap
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 700);
+
+            // only 200 is left of the daily cap
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(200_000);
+            assert_eq!(Parking::parking_session(&1, 200_000).unwrap().current_fee, 200);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 500);
+            assert_eq!(Parking::user_daily_fees(&parking_lot_hash, &1).len(), 3);
+
+            // the earlier sessions leave the window after 24 hours
+            let day = DAILY_CAP_WINDOW;
+            timestamp::Module::<Test>::set_timestamp(day + 200_000);
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(day + 300_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 200);
+            assert_eq!(Parking::user_daily_fees(&parking_lot_hash, &1).len(), 1);
+
+            // the fees settled when the parking lot changes hands are limited as well
+            let transfer_rules = FeeRules {
+                grace_period: 60_000,
+                session_cap: Some(300),
+                daily_cap: Some(5_000),
+            };
+            assert_ok!(Parking::set_fee_rules(Origin::signed(owner), parking_lot_hash, transfer_rules));
+            assert_ok!(enter(3, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(day + 330_000);
+            assert_ok!(Parking::transfer_parking_lot(Origin::signed(owner), parking_lot_hash, 2));
+            assert_eq!(Parking::user_parking_info(3).unwrap().paid_fee, 0);
+            timestamp::Module::<Test>::set_timestamp(day + 400_000);
+            assert_ok!(Parking::transfer_parking_lot(Origin::signed(2), parking_lot_hash, owner));
+            assert_eq!(Parking::user_parking_info(3).unwrap().paid_fee, 300);
+            assert_eq!(balances::Module::<Test>::free_balance(2), 1300);
+            timestamp::Module::<Test>::set_timestamp(day + 500_000);
+            assert_ok!(Parking::leaving(Origin::signed(3), None));
+            assert_eq!(balances::Module::<Test>::free_balance(3), 700);
+
+            // the recent fees are dropped with the parking lot
+            assert_ok!(Parking::remove_parking_lot(Origin::signed(owner), parking_lot_hash));
+            assert!(!<UserDailyFees<Test>>::exists(&parking_lot_hash, &3));
+
+            // the rule that limited the fee
+            let mut parking_info = ParkingInfo::<Test>::new(1, parking_lot_hash, H256::default(), 0);
+            parking_info.current_time = day + day / 2;
+            parking_info.current_fee = 10_000;
+            assert_eq!(Parking::limit_fee(&parking_info, &rules, 0), Ok((300, FeeLimit::SessionCap)));
+            let rules = FeeRules {
+                grace_period: 0,
+                session_cap: None,
+                daily_cap: Some(500),
+            };
+            // a session of one and a half days is capped twice
+            assert_eq!(Parking::limit_fee(&parking_info, &rules, 100), Ok((900, FeeLimit::DailyCap)));
+            parking_info.current_fee = 800;
+            assert_eq!(Parking::limit_fee(&parking_info, &rules, 0), Ok((800, FeeLimit::Unlimited)));
+        })
+    }
+
+    #[test]
+    fn test_billing() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            // parts of a second are carried between accruals: 500ms at 19, 700ms at 28 and 800ms at 19
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(500);
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(1_200);
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+            timestamp::Module::<Test>::set_timestamp(2_000);
+            assert_eq!(Parking::parking_session(&1, 2_000).unwrap().metered_fee, 44_300);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 956);
+            // less than a second is not billed by default
+            assert_eq!(balances::Module::<Test>::free_balance(2), 1000);
+
+            let billing = Billing {
+                granularity: Granularity::Second,
+                rounding: Rounding::Up,
+            };
+            assert_err!(
+                Parking::set_billing(Origin::signed(1), parking_lot_hash, billing),
+                "Only the owner can change the parking lot billing"
+            );
+            assert_ok!(Parking::set_billing(Origin::signed(owner), parking_lot_hash, billing));
+            // 1.4s at 19 is billed as 2s
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(3_400);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 918);
+
+            let billing = Billing {
+                granularity: Granularity::QuarterHour,
+                rounding: Rounding::Nearest,
+            };
+            assert_eq!(billing.billed_millis(7 * 60_000), 0);
+            assert_eq!(billing.billed_millis(7 * 60_000 + 30_000), 900_000);
+            let billing = Billing {
+                granularity: Granularity::Minute,
+                rounding: Rounding::Up,
+            };
+            assert_eq!(billing.billed_millis(60_000), 60_000);
+            assert_eq!(billing.billed_millis(60_001), 120_000);
+        })
+    }
+
+    #[test]
+    fn test_large_balance() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let rich = 6;
+            let min_price = 100_000_000_000_000_000_000;
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 0, 0, 2, min_price, 3 * min_price));
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 1));
+
+            // 2 * 10^20 per second with one of two positions parked
+            assert_ok!(enter(rich, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().current_price, 2 * min_price);
+            assert_eq!(Parking::quote_fee(parking_lot_hash, SpotCategory::Standard, 3600), Some(3600 * 3 * min_price));
+
+            timestamp::Module::<Test>::set_timestamp(10_500);
+            assert_eq!(Parking::parking_lot_fee_index(parking_lot_hash), 0);
+            assert_ok!(Parking::leaving(Origin::signed(rich), None));
+            assert_eq!(Parking::parking_lot_fee_index(parking_lot_hash), 10_500 * 2 * min_price);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1000 + 20 * min_price);
+            assert_eq!(
+                balances::Module::<Test>::free_balance(rich),
+                1_000_000_000_000_000_000_000_000_000 - 20 * min_price
+            );
+
+            // three weeks and a half second, the half second is not billed
+            let weeks = 3 * 7 * 24 * 3600;
+            assert_ok!(enter(rich, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(10_500 + weeks * 1000 + 500);
+            assert_ok!(Parking::leaving(Origin::signed(rich), None));
+            assert_eq!(
+                balances::Module::<Test>::free_balance(owner),
+                1000 + 20 * min_price + u128::from(weeks) * 2 * min_price
+            );
+        })
+    }
+
+    #[test]
+    fn test_price_guard() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+
+            // the price is 19 after entering
+            assert_err!(
+                Parking::entering(
+                    Origin::signed(1),
+                    parking_lot_hash,
+                    SpotCategory::Standard,
+                    18,
+                    None,
+                    GuardAction::Alert,
+                    None
+                ),
+                "The parking lot price is above the user's maximum price"
+            );
+            assert!(Parking::user_parking_info(1).is_none());
+            assert!(Parking::guarded_sessions().is_empty());
+            assert_ok!(Parking::entering(
+                Origin::signed(1),
+                parking_lot_hash,
+                SpotCategory::Standard,
+                19,
+                None,
+                GuardAction::Alert,
+                None
+            ));
+            assert_ok!(Parking::entering(
+                Origin::signed(2),
+                parking_lot_hash,
+                SpotCategory::Standard,
+                28,
+                Some(50),
+                GuardAction::Leave,
+                None
+            ));
+            assert_eq!(Parking::guarded_sessions(), vec![1, 2]);
+
+            // the price is 28 for the first user now, who is alerted and still parked
+            Parking::on_finalize(1);
+            assert_eq!(Parking::guarded_sessions(), vec![2]);
+            assert!(Parking::user_parking_info(1).is_some());
+
+            // 28 after 1s is within the bound of the second user, 56 after 2s is not. The sessions are checked
+            // when the block is finalized, at the time of the block
+            timestamp::Module::<Test>::set_timestamp(1000);
+            Parking::on_finalize(2);
+            assert!(Parking::user_parking_info(2).is_some());
+            timestamp::Module::<Test>::set_timestamp(2000);
+            Parking::on_initialize(3);
+            assert!(Parking::user_parking_info(2).is_some());
+            Parking::on_finalize(3);
+            assert!(Parking::user_parking_info(2).is_none());
+            assert!(Parking::guarded_sessions().is_empty());
+            assert_eq!(balances::Module::<Test>::free_balance(2), 944);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().current_price, 19);
+
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+        })
+    }
+
+    #[test]
+    fn test_price_guard_with_pass() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(Parking::set_pass_product(Origin::signed(owner), parking_lot_hash, 0, 300, 100_000, 1));
+            assert_ok!(Parking::buy_pass(Origin::signed(1), parking_lot_hash, 0, false));
+            assert_ok!(Parking::entering(
+                Origin::signed(1),
+                parking_lot_hash,
+                SpotCategory::Standard,
+                19,
+                None,
+                GuardAction::Leave,
+                None
+            ));
+
+            // the pass holder pays nothing whatever the price is raised to
+            assert_ok!(Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, 0, 0, 10, 50, 100));
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            Parking::on_finalize(1);
+            assert!(Parking::user_parking_info(1).is_some());
+            assert_eq!(Parking::guarded_sessions(), vec![1]);
+
+            // the price is guarded again once the pass expires
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            Parking::on_initialize(2);
+            Parking::on_finalize(2);
+            assert!(Parking::user_parking_info(1).is_none());
+            assert!(Parking::guarded_sessions().is_empty());
+            assert_eq!(balances::Module::<Test>::free_balance(1), 700);
+        })
+    }
+
+    #[test]
+    fn test_locked_rate() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::set_locked_rate(Origin::signed(1), parking_lot_hash, true),
+                "Only the owner can change the parking lot rates"
+            );
+            assert_ok!(Parking::set_locked_rate(Origin::signed(owner), parking_lot_hash, true));
+
+            // the first user keeps 19, the second one enters after the locked rates are disabled
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::user_parking_info(1).unwrap().locked_price, Some(19));
+            assert_ok!(Parking::set_locked_rate(Origin::signed(owner), parking_lot_hash, false));
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::user_parking_info(2).unwrap().locked_price, None);
+
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_eq!(Parking::parking_session(&1, 10_000).unwrap().current_fee, 190);
+            assert_eq!(Parking::parking_session(&2, 10_000).unwrap().current_fee, 280);
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+
+            // a schedule set during the stay does not change the locked price
+            let schedule = vec![ScheduleEntry {
+                start: 0,
+                end: MINUTES_PER_WEEK,
+                rule: PriceRule::Percent(200),
+            }];
+            assert_ok!(Parking::set_price_schedule(Origin::signed(owner), parking_lot_hash, schedule));
+            timestamp::Module::<Test>::set_timestamp(20_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 620);
+            assert_eq!(balances::Module::<Test>::free_balance(2), 720);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1660);
+
+            // the price locked at entering follows the schedule of that time, and only of that time
+            assert_ok!(Parking::set_locked_rate(Origin::signed(owner), parking_lot_hash, true));
+            assert_ok!(enter(3, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::user_parking_info(3).unwrap().locked_price, Some(38));
+            assert_ok!(Parking::set_price_schedule(Origin::signed(owner), parking_lot_hash, vec![]));
+            timestamp::Module::<Test>::set_timestamp(30_000);
+            assert_eq!(Parking::parking_session(&3, 30_000).unwrap().current_fee, 380);
+        })
+    }
+
+    #[test]
+    fn test_prepaid_session() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let rich = 6;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::entering_prepaid(Origin::signed(rich), parking_lot_hash, 1),
+                "The parking lot does not take prepaid sessions"
+            );
+            assert_err!(
+                Parking::set_overstay_rate(Origin::signed(1), parking_lot_hash, Some(50)),
+                "Only the owner can change the parking lot overstay rate"
+            );
+            assert_ok!(Parking::set_overstay_rate(Origin::signed(owner), parking_lot_hash, Some(50)));
+            // unlocking the rates keeps the overstay rate
+            assert_ok!(Parking::set_locked_rate(Origin::signed(owner), parking_lot_hash, false));
+            assert_eq!(Parking::parking_lot_overstay_rate(parking_lot_hash), Some(50));
+            assert_err!(
+                Parking::entering_prepaid(Origin::signed(rich), parking_lot_hash, 0),
+                "Prepaid minutes are out of range"
+            );
+
+            // a minute at 19 is paid at entering
+            assert_ok!(Parking::entering_prepaid(Origin::signed(rich), parking_lot_hash, 1));
+            assert_eq!(Parking::user_parking_info(rich).unwrap().paid_until, Some(60_000));
+            assert_eq!(Parking::prepaid_ends(1), vec![(60_000, rich)]);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1000 + 1140);
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert_err!(Parking::extend(Origin::signed(1), 1), "The parking session is not prepaid");
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+
+            // the extension starts when the prepaid time ends
+            timestamp::Module::<Test>::set_timestamp(30_000);
+            assert_ok!(Parking::extend(Origin::signed(rich), 1));
+            assert_eq!(Parking::user_parking_info(rich).unwrap().paid_until, Some(120_000));
+            assert!(Parking::prepaid_ends(1).is_empty());
+            assert_eq!(Parking::prepaid_ends(2), vec![(120_000, rich)]);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1000 + 2280);
+
+            // 10s of overstay at 50 after the prepaid time has run out
+            timestamp::Module::<Test>::set_timestamp(130_000);
+            Parking::on_finalize(1);
+            assert!(Parking::prepaid_ends(2).is_empty());
+            assert_eq!(Parking::prepaid_end_cursor(), Some(2));
+            assert_eq!(Parking::parking_session(&rich, 130_000).unwrap().current_fee, 2780);
+            assert_ok!(Parking::leaving(Origin::signed(rich), None));
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1000 + 2780);
+            assert_eq!(
+                balances::Module::<Test>::free_balance(rich),
+                1_000_000_000_000_000_000_000_000_000 - 2780
+            );
+        })
+    }
+
+    #[test]
+    fn test_pass() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::set_pass_product(Origin::signed(1), parking_lot_hash, 0, 300, 100_000, 1),
+                "Only the owner can change the parking lot passes"
+            );
+            assert_err!(
+                Parking::set_pass_product(Origin::signed(owner), parking_lot_hash, 1, 300, 100_000, 1),
+                "The pass product has not existed"
+            );
+            assert_ok!(Parking::set_pass_product(Origin::signed(owner), parking_lot_hash, 0, 300, 100_000, 1));
+
+            // the pass holds a position
+            assert_ok!(Parking::buy_pass(Origin::signed(1), parking_lot_hash, 0, false));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 700);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 9);
+            assert_err!(
+                Parking::buy_pass(Origin::signed(2), parking_lot_hash, 0, false),
+                "The pass product is sold out"
+            );
+
+            // the pass does not open a parking lot which is not open
+            assert_ok!(Parking::set_parking_lot_status(Origin::signed(owner), parking_lot_hash, LotStatus::Closed));
+            assert_err!(enter(1, parking_lot_hash, SpotCategory::Standard), "The parking lot is not open");
+            assert_ok!(Parking::set_parking_lot_status(Origin::signed(owner), parking_lot_hash, LotStatus::Open));
+
+            // entering and leaving are free while the pass is valid
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            assert!(Parking::user_parking_info(1).unwrap().pass);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 9);
+            timestamp::Module::<Test>::set_timestamp(50_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 700);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 9);
+
+            // the user pays 19 per second after the pass expires
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            Parking::on_initialize(1);
+            assert!(Parking::user_passes((1, parking_lot_hash)).is_none());
+            assert!(!Parking::user_parking_info(1).unwrap().pass);
+            assert_eq!(Parking::pass_products(parking_lot_hash)[0].sold, 0);
+            timestamp::Module::<Test>::set_timestamp(110_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 510);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 10);
+
+            // an auto-renewed pass is charged again when it expires
+            assert_ok!(Parking::buy_pass(Origin::signed(2), parking_lot_hash, 0, true));
+            timestamp::Module::<Test>::set_timestamp(210_000);
+            Parking::on_initialize(2);
+            assert_eq!(Parking::user_passes((2, parking_lot_hash)).unwrap().expires, 310_000);
+            assert_eq!(Parking::pass_expiries(5), vec![(310_000, 2, parking_lot_hash)]);
+            assert_eq!(balances::Module::<Test>::free_balance(2), 400);
+
+            assert_ok!(Parking::set_pass_auto_renew(Origin::signed(2), parking_lot_hash, false));
+            timestamp::Module::<Test>::set_timestamp(310_000);
+            Parking::on_initialize(3);
+            assert!(Parking::user_passes((2, parking_lot_hash)).is_none());
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 10);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 2090);
+        })
+    }
+
+    #[test]
+    fn test_pass_billing() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let rich = 6;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(Parking::set_pass_product(Origin::signed(owner), parking_lot_hash, 0, 300, 100_000, 1));
+            let billing = Billing {
+                granularity: Granularity::Minute,
+                rounding: Rounding::Up,
+            };
+            assert_ok!(Parking::set_billing(Origin::signed(owner), parking_lot_hash, billing));
+            assert_ok!(Parking::buy_pass(Origin::signed(rich), parking_lot_hash, 0, false));
+            assert_ok!(enter(rich, parking_lot_hash, SpotCategory::Standard));
+
+            // the 10s after the pass expires are billed as a minute at 19, the free time is not billed with them
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            Parking::on_initialize(1);
+            assert_eq!(Parking::user_parking_info(rich).unwrap().pass_ended, Some(100_000));
+            timestamp::Module::<Test>::set_timestamp(110_000);
+            assert_ok!(Parking::leaving(Origin::signed(rich), None));
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1000 + 300 + 60 * 19);
+        })
+    }
+
+    #[test]
+    fn test_coupon() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            let code = b"SPRING".to_vec();
+            let code_hash = BlakeTwo256::hash(&code);
+            let half = Discount::Percent(50);
+            assert_err!(
+                Parking::issue_coupon(Origin::signed(1), code_hash, Some(parking_lot_hash), half, 1, 100_000),
+                "Only the owner can issue coupons of the parking lot"
+            );
+            assert_ok!(Parking::issue_coupon(Origin::signed(owner), code_hash, None, half, 1, 100_000));
+            assert_err!(
+                Parking::issue_coupon(Origin::signed(owner), code_hash, None, half, 1, 100_000),
+                "The coupon has existed"
+            );
+            let fixed_code = b"FIXED".to_vec();
+            let fixed_hash = BlakeTwo256::hash(&fixed_code);
+            let fixed = Discount::Fixed(100);
+            assert_ok!(Parking::issue_coupon(
+                Origin::signed(owner),
+                fixed_hash,
+                Some(parking_lot_hash),
+                fixed,
+                5,
+                100_000
+            ));
+
+            // half of 190 with the coupon applied at entering
+            let entering = |user, coupon| {
+                Parking::entering(
+                    Origin::signed(user),
+                    parking_lot_hash,
+                    SpotCategory::Standard,
+                    u128::max_value(),
+                    None,
+                    GuardAction::Ignore,
+                    Some(coupon),
+                )
+            };
+            assert_err!(entering(1, b"WRONG".to_vec()), "The coupon has not existed");
+            assert!(Parking::user_parking_info(1).is_none());
+            assert_ok!(entering(1, code.clone()));
+            assert_eq!(Parking::coupons(code_hash).unwrap().uses_left, 0);
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_eq!(Parking::parking_session(&1, 10_000).unwrap().current_fee, 95);
+            assert_err!(
+                Parking::leaving(Origin::signed(1), Some(fixed_code.clone())),
+                "A coupon is already applied to the parking session"
+            );
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 905);
+            assert_err!(entering(2, code.clone()), "The coupon has been used up");
+
+            // the fixed coupon is only for the first parking lot of the owner
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 0, 0, 10, 10, 100));
+            let other = Parking::owner_parking_lots_array((owner, 1));
+            assert_ok!(enter(2, other, SpotCategory::Standard));
+            assert_err!(
+                Parking::leaving(Origin::signed(2), Some(fixed_code.clone())),
+                "The coupon is not for the parking lot"
+            );
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+
+            // 190 less 100 with the coupon applied at leaving
+            assert_ok!(enter(3, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(20_000);
+            assert_ok!(Parking::leaving(Origin::signed(3), Some(fixed_code.clone())));
+            assert_eq!(balances::Module::<Test>::free_balance(3), 910);
+            assert_eq!(Parking::coupons(fixed_hash).unwrap().uses_left, 4);
+
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            assert_ok!(enter(3, parking_lot_hash, SpotCategory::Standard));
+            assert_err!(Parking::leaving(Origin::signed(3), Some(fixed_code)), "The coupon has expired");
+            assert_ok!(Parking::leaving(Origin::signed(3), None));
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1185);
+
+            assert_err!(
+                Parking::revoke_coupon(Origin::signed(1), fixed_hash),
+                "Only the owner who issued the coupon can revoke it"
+            );
+            assert_eq!(Parking::parking_lot_coupons(parking_lot_hash), vec![fixed_hash]);
+            assert_ok!(Parking::revoke_coupon(Origin::signed(owner), fixed_hash));
+            assert!(Parking::coupons(fixed_hash).is_none());
+            assert!(Parking::parking_lot_coupons(parking_lot_hash).is_empty());
+
+            // the coupons for a removed parking lot are removed with it, the ones for all parking lots stay
+            let other_hash = BlakeTwo256::hash(b"OTHER");
+            assert_ok!(Parking::issue_coupon(Origin::signed(owner), other_hash, Some(other), half, 1, 200_000));
+            assert_ok!(Parking::remove_parking_lot(Origin::signed(owner), other));
+            assert!(Parking::coupons(other_hash).is_none());
+            assert!(Parking::parking_lot_coupons(other).is_empty());
+            assert!(Parking::coupons(code_hash).is_some());
+        })
+    }
+
+    #[test]
+    fn test_commission() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            let treasury = Parking::treasury_account();
+            assert_eq!(Parking::commission(parking_lot_hash), Permill::zero());
+            assert!(Parking::set_commission(Origin::signed(owner), Some(Permill::from_percent(10))).is_err());
+            assert_ok!(Parking::set_commission(system::RawOrigin::Root.into(), Some(Permill::from_percent(10))));
+
+            // 19 of the fee of 190 goes to the treasury
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_ok!(Parking::leaving(Origin::signed(1), None));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 810);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1171);
+            assert_eq!(balances::Module::<Test>::free_balance(treasury), 19);
+
+            // the commission of the parking lot overrides the commission of all parking lots
+            assert_err!(
+                Parking::set_lot_commission(system::RawOrigin::Root.into(), H256::zero(), None),
+                "The parking lot has not existed"
+            );
+            assert!(Parking::set_lot_commission(Origin::signed(owner), parking_lot_hash, None).is_err());
+            let half = Some(Permill::from_percent(50));
+            assert_ok!(Parking::set_lot_commission(system::RawOrigin::Root.into(), parking_lot_hash, half));
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(20_000);
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1266);
+            assert_eq!(balances::Module::<Test>::free_balance(treasury), 114);
+
+            assert_ok!(Parking::set_lot_commission(system::RawOrigin::Root.into(), parking_lot_hash, None));
+            assert_eq!(Parking::commission(parking_lot_hash), Permill::from_percent(10));
+            assert_ok!(Parking::set_commission(system::RawOrigin::Root.into(), None));
+            assert_eq!(Parking::commission(parking_lot_hash), Permill::zero());
+
+            assert!(Parking::spend_treasury(Origin::signed(owner), 3, 100).is_err());
+            assert_ok!(Parking::spend_treasury(system::RawOrigin::Root.into(), 3, 100));
+            assert_eq!(balances::Module::<Test>::free_balance(treasury), 14);
+            assert_eq!(balances::Module::<Test>::free_balance(3), 1100);
+            assert!(Parking::spend_treasury(system::RawOrigin::Root.into(), 3, 100).is_err());
+        })
+    }
+
+    #[test]
+    fn test_entering_deposit() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let user = 1;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                enter(5, parking_lot_hash, SpotCategory::Standard),
+                "Not enough balance for the parking deposit"
+            );
+            assert!(Parking::user_parking_info(5).is_none());
+
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::user_parking_info(user).unwrap().deposit, 100);
+            assert_eq!(balances::Module::<Test>::reserved_balance(user), 100);
+            assert_eq!(balances::Module::<Test>::free_balance(user), 900);
+
+            // the fee is paid from the deposit and the rest of the deposit is refunded
+            timestamp::Module::<Test>::set_timestamp(2_000);
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+            assert_eq!(balances::Module::<Test>::reserved_balance(user), 0);
+            assert_eq!(balances::Module::<Test>::free_balance(user), 962);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1038);
+        })
+    }
+
+    #[test]
+    fn test_leaving_with_fee_shortfall() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let user = 4;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+
+            // 1900 is more than the deposit and the free balance, the user can still leave
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+            assert!(Parking::user_parking_info(user).is_none());
+            assert_eq!(balances::Module::<Test>::reserved_balance(user), 0);
+            assert_eq!(balances::Module::<Test>::free_balance(user), 50);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1100);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 10);
+            assert_eq!(Parking::user_debts(user), vec![(owner, 1800)]);
+        })
+    }
+
+    #[test]
+    fn test_pay_debt() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let user = 4;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            assert_ok!(Parking::leaving(Origin::signed(user), None));
+
+            assert_err!(enter(user, parking_lot_hash, SpotCategory::Standard), "User has outstanding parking debt");
+            assert_err!(
+                Parking::pay_debt(Origin::signed(user), 1),
+                "User has no debt to the account"
+            );
+            assert!(Parking::pay_debt(Origin::signed(user), owner).is_err());
+            assert_eq!(Parking::user_debts(user), vec![(owner, 1800)]);
+
+            let _ = balances::Module::<Test>::make_free_balance_be(&user, 2000);
+            assert_ok!(Parking::pay_debt(Origin::signed(user), owner));
+            assert!(Parking::user_debts(user).is_empty());
+            assert_eq!(balances::Module::<Test>::free_balance(user), 200);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 2900);
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+        })
+    }
+
+    #[test]
+    fn test_reserve_spot() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let user = 1;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_err!(
+                Parking::reserve_spot(Origin::signed(user), parking_lot_hash, 0),
+                "Arriving deadline must be in the future"
+            );
+            assert_err!(
+                Parking::reserve_spot(Origin::signed(user), parking_lot_hash, 3_600_001),
+                "Arriving deadline is too far in the future"
+            );
+
+            assert_ok!(Parking::reserve_spot(Origin::signed(user), parking_lot_hash, 60_000));
+            assert_eq!(balances::Module::<Test>::reserved_balance(user), 50);
+            let parking_lot = Parking::parking_lots(parking_lot_hash).unwrap();
+            assert_eq!(parking_lot.remain, 9);
+            assert_eq!(parking_lot.current_price, 19);
+            assert_eq!(Parking::reservation_deadlines(1), vec![(60_000, user)]);
+            assert_err!(
+                Parking::reserve_spot(Origin::signed(user), parking_lot_hash, 60_000),
+                "User already has reserved a parking lot"
+            );
+            let other = Parking::owner_parking_lots_array((1, 0));
+            assert_err!(enter(user, other, SpotCategory::Standard), "User has reserved another parking lot");
+
+            // the reservation is consumed and its deposit returned, the position is already taken
+            timestamp::Module::<Test>::set_timestamp(30_000);
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            assert!(Parking::reservations(user).is_none());
+            assert!(Parking::reservation_deadlines(1).is_empty());
+            assert_eq!(balances::Module::<Test>::reserved_balance(user), 100);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 9);
+        })
+    }
+
+    #[test]
+    fn test_timer_buckets_catch_up() {
+        with_externalities(&mut new_test_ext(), || {
+            let parking_lot_hash = Parking::owner_parking_lots_array((0, 0));
+            assert_eq!(Parking::reservation_deadline_cursor(), None);
+            assert_ok!(Parking::reserve_spot(Origin::signed(1), parking_lot_hash, 60_000));
+            assert_eq!(Parking::reservation_deadline_cursor(), Some(1));
+
+            // a block walks at most `MAX_TIMER_BUCKETS` buckets, the next blocks walk the rest
+            timestamp::Module::<Test>::set_timestamp(100 * TIMER_BUCKET + 1);
+            Parking::on_initialize(1);
+            assert!(Parking::reservations(1).is_none());
+            assert_eq!(Parking::reservation_deadline_cursor(), Some(1 + MAX_TIMER_BUCKETS));
+            Parking::on_initialize(2);
+            assert_eq!(Parking::reservation_deadline_cursor(), Some(100));
+
+            // a new deadline is never behind the cursor
+            assert_ok!(Parking::reserve_spot(Origin::signed(2), parking_lot_hash, 100 * TIMER_BUCKET + 60_000));
+            assert_eq!(Parking::reservation_deadlines(101), vec![(100 * TIMER_BUCKET + 60_000, 2)]);
+            assert_eq!(Parking::reservation_deadline_cursor(), Some(100));
+        })
+    }
+
+    #[test]
+    fn test_reservation_holds_full_parking_lot() {
+        with_externalities(&mut new_test_ext(), || {
+            let user = 2;
+            assert_ok!(Parking::new_parking_lot(Origin::signed(1), 50, 50, 1, 10, 100));
+            let parking_lot_hash = Parking::owner_parking_lots_array((1, 1));
+            assert_ok!(Parking::reserve_spot(Origin::signed(user), parking_lot_hash, 60_000));
+            assert_err!(enter(3, parking_lot_hash, SpotCategory::Standard), "The parking lot has no more position");
+            assert_ok!(Parking::set_parking_lot_status(Origin::signed(1), parking_lot_hash, LotStatus::Closed));
+            assert_ok!(enter(user, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 0);
+        })
+    }
+
+    #[test]
+    fn test_reservation_expired() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            assert_ok!(Parking::reserve_spot(Origin::signed(1), parking_lot_hash, 60_000));
+            assert_ok!(Parking::reserve_spot(Origin::signed(2), parking_lot_hash, 120_000));
+            assert_eq!(Parking::reservation_deadlines(1), vec![(60_000, 1)]);
+            assert_eq!(Parking::reservation_deadlines(2), vec![(120_000, 2)]);
+
+            timestamp::Module::<Test>::set_timestamp(60_000);
+            Parking::on_initialize(1);
+            assert!(Parking::reservations(1).is_some());
+
+            timestamp::Module::<Test>::set_timestamp(60_001);
+            Parking::on_initialize(2);
+            assert!(Parking::reservations(1).is_none());
+            assert!(!<ReservationDeadlines<Test>>::exists(1));
+            assert_eq!(Parking::reservation_deadlines(2), vec![(120_000, 2)]);
+            assert_eq!(balances::Module::<Test>::reserved_balance(1), 0);
+            assert_eq!(balances::Module::<Test>::free_balance(1), 950);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1050);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 9);
+        })
+    }
+
+    #[test]
+    fn test_booking_capacity() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 1;
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 50, 50, 1, 10, 100));
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 1));
+
+            assert_err!(Parking::book(Origin::signed(2), parking_lot_hash, vec![]), "No booking window");
+            assert_err!(
+                Parking::book(Origin::signed(2), parking_lot_hash, vec![(0, 10_000)]),
+                "Booking window must be in the future"
+            );
+            assert_err!(
+                Parking::book(Origin::signed(2), parking_lot_hash, vec![(10_000, 10_000)]),
+                "Booking window must end after it starts"
+            );
+
+            assert_ok!(Parking::book(Origin::signed(2), parking_lot_hash, vec![(10_000, 20_000)]));
+            assert_eq!(balances::Module::<Test>::reserved_balance(2), 100);
+            let booking = Parking::bookings(Parking::user_bookings(2)[0]).unwrap();
+            assert_eq!((booking.start, booking.end, booking.price, booking.amount), (10_000, 20_000, 10, 100));
+
+            assert_err!(
+                Parking::book(Origin::signed(3), parking_lot_hash, vec![(15_000, 25_000)]),
+                "The parking lot is fully booked"
+            );
+            // windows do not include their ends
+            assert_ok!(Parking::book(Origin::signed(3), parking_lot_hash, vec![(20_000, 30_000)]));
+
+            // windows of the same call are checked against each other, nothing is booked if one fails
+            assert_err!(
+                Parking::book(Origin::signed(0), parking_lot_hash, vec![(30_000, 40_000), (35_000, 45_000)]),
+                "The parking lot is fully booked"
+            );
+            assert!(Parking::user_bookings(0).is_empty());
+            assert_eq!(balances::Module::<Test>::reserved_balance(0), 0);
+            assert_eq!(Parking::parking_lot_bookings(parking_lot_hash).len(), 2);
+
+            assert_err!(
+                Parking::update_parking_lot(Origin::signed(owner), parking_lot_hash, 50, 50, 0, 10, 100),
+                "Capacity cannot be less than the number of booked positions"
+            );
+            assert_err!(
+                Parking::remove_parking_lot(Origin::signed(owner), parking_lot_hash),
+                "The parking lot still has bookings"
+            );
+        })
+    }
+
+    #[test]
+    fn test_entering_with_booking() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 1;
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 50, 50, 1, 10, 100));
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 1));
+            assert_ok!(Parking::book(Origin::signed(2), parking_lot_hash, vec![(10_000, 20_000)]));
+            assert_ok!(Parking::book(Origin::signed(3), parking_lot_hash, vec![(20_000, 30_000)]));
+
+            // the booked position is kept for its user during the window
+            timestamp::Module::<Test>::set_timestamp(12_000);
+            assert_err!(enter(0, parking_lot_hash, SpotCategory::Standard), "The parking lot has no more position");
+
+            // the booked amount is paid at entering
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1100);
+            assert_eq!(balances::Module::<Test>::reserved_balance(2), 100);
+            let parking_info = Parking::user_parking_info(2).unwrap();
+            assert_eq!(parking_info.paid_fee, 100);
+            assert_eq!(parking_info.booking, Some((20_000, 10)));
+            assert_eq!(Parking::parking_lot_bookings(parking_lot_hash).len(), 1);
+
+            // the stay after the window is charged at the booked price
+            timestamp::Module::<Test>::set_timestamp(25_000);
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1150);
+            assert_eq!(balances::Module::<Test>::free_balance(2), 850);
+            assert_eq!(balances::Module::<Test>::reserved_balance(2), 0);
+
+            // the user did not come during the window
+            timestamp::Module::<Test>::set_timestamp(30_000);
+            Parking::on_initialize(1);
+            assert!(Parking::user_bookings(3).is_empty());
+            assert!(Parking::parking_lot_bookings(parking_lot_hash).is_empty());
+            assert!(!<BookingEnds<Test>>::exists(0));
+            assert_eq!(balances::Module::<Test>::reserved_balance(3), 0);
+            assert_eq!(balances::Module::<Test>::free_balance(3), 900);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1250);
+        })
+    }
+
+    #[test]
+    fn test_booking_held_at_window_start() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 1;
+            let rich = 6;
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 50, 50, 1, 10, 100));
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 1));
+            assert_ok!(Parking::book(Origin::signed(2), parking_lot_hash, vec![(100_000, 110_000)]));
+            let booking_id = Parking::user_bookings(2)[0];
+
+            // a walk-in takes the only position before the window, the booking is refunded when it starts
+            timestamp::Module::<Test>::set_timestamp(30_000);
+            assert_ok!(enter(rich, parking_lot_hash, SpotCategory::Standard));
+            Parking::on_finalize(1);
+            assert!(!Parking::bookings(booking_id).unwrap().held);
+            timestamp::Module::<Test>::set_timestamp(100_000);
+            Parking::on_finalize(2);
+            assert!(Parking::bookings(booking_id).is_none());
+            assert!(Parking::user_bookings(2).is_empty());
+            assert_eq!(balances::Module::<Test>::reserved_balance(2), 0);
+            assert_eq!(balances::Module::<Test>::free_balance(2), 1000);
+            assert_ok!(Parking::leaving(Origin::signed(rich), None));
+
+            // the position is held when the window starts, walk-ins cannot take it before it is held either
+            assert_ok!(Parking::book(Origin::signed(2), parking_lot_hash, vec![(200_000, 210_000)]));
+            let booking_id = Parking::user_bookings(2)[0];
+            timestamp::Module::<Test>::set_timestamp(200_000);
+            assert_err!(enter(rich, parking_lot_hash, SpotCategory::Standard), "The parking lot has no more position");
+            Parking::on_finalize(3);
+            assert!(Parking::bookings(booking_id).unwrap().held);
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 0);
+            assert_err!(enter(rich, parking_lot_hash, SpotCategory::Standard), "The parking lot has no more position");
+            assert_ok!(enter(2, parking_lot_hash, SpotCategory::Standard));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 0);
+            assert_ok!(Parking::leaving(Origin::signed(2), None));
+            assert_eq!(Parking::parking_lots(parking_lot_hash).unwrap().remain, 1);
+
+            // the positions held by passes cannot be booked
+            assert_ok!(Parking::set_pass_product(Origin::signed(owner), parking_lot_hash, 0, 10, 1_000_000, 1));
+            assert_ok!(Parking::buy_pass(Origin::signed(3), parking_lot_hash, 0, false));
+            assert_err!(
+                Parking::book(Origin::signed(2), parking_lot_hash, vec![(300_000, 310_000)]),
+                "The parking lot is fully booked"
+            );
+        })
+    }
+
+    #[test]
+    fn test_cancel_booking() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            let rules = BookingRules {
+                free_cancel_notice: 10_000,
+                late_refund_percent: 50,
+            };
+            assert_err!(
+                Parking::set_booking_rules(Origin::signed(1), parking_lot_hash, rules),
+                "Only the owner can change the booking rules"
+            );
+            assert_err!(
+                Parking::set_booking_rules(Origin::signed(owner), parking_lot_hash, BookingRules {
+                    free_cancel_notice: 10_000,
+                    late_refund_percent: 101,
+                }),
+                "Refund percent cannot be greater than 100"
+            );
+            assert_ok!(Parking::set_booking_rules(Origin::signed(owner), parking_lot_hash, rules));
+
+            for user in 1..4 {
+                assert_ok!(Parking::book(Origin::signed(user), parking_lot_hash, vec![(60_000, 70_000)]));
+            }
+            let booking_id = |user| Parking::user_bookings(user)[0];
+
+            // cancelled early enough
+            timestamp::Module::<Test>::set_timestamp(40_000);
+            assert_err!(
+                Parking::cancel_booking(Origin::signed(2), booking_id(1)),
+                "Only the user can cancel the booking"
+            );
+            assert_ok!(Parking::cancel_booking(Origin::signed(1), booking_id(1)));
+            assert_eq!(balances::Module::<Test>::free_balance(1), 1000);
+            assert_eq!(balances::Module::<Test>::reserved_balance(1), 0);
+
+            // cancelled late, half of the amount goes to the owner
+            timestamp::Module::<Test>::set_timestamp(55_000);
+            assert_ok!(Parking::cancel_booking(Origin::signed(2), booking_id(2)));
+            assert_eq!(balances::Module::<Test>::free_balance(2), 950);
+            assert_eq!(balances::Module::<Test>::reserved_balance(2), 0);
+            assert_eq!(balances::Module::<Test>::free_balance(owner), 1050);
+
+            timestamp::Module::<Test>::set_timestamp(65_000);
+            assert_err!(
+                Parking::cancel_booking(Origin::signed(3), booking_id(3)),
+                "The booking window has started"
+            );
+            assert_eq!(Parking::parking_lot_bookings(parking_lot_hash).len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_queries() {
+        with_externalities(&mut new_test_ext(), || {
+            assert_eq!(Parking::parking_lots_page(0, 1).len(), 1);
+            assert_eq!(Parking::parking_lots_page(1, 10).len(), 1);
+            assert!(Parking::parking_lots_page(5, 10).is_empty());
+
+            let owner = 0;
+            let parking_lot_hash = Parking::owner_parking_lots_array((owner, 0));
+            let parking_lots = Parking::owner_parking_lots(&owner);
+            assert_eq!(parking_lots.len(), 1);
+            assert_eq!(parking_lots[0].0, parking_lot_hash);
+
+            assert!(Parking::parking_session(&1, 10_000).is_none());
+            assert_ok!(enter(1, parking_lot_hash, SpotCategory::Standard));
+            // 10s at the price of one parked user
+            let parking_info = Parking::parking_session(&1, 10_000).unwrap();
+            assert_eq!(parking_info.current_fee, 190);
+            assert_eq!(parking_info.current_time, 10_000);
+            // nothing is written
+            assert_eq!(Parking::user_parking_info(1).unwrap().current_fee, 0);
+            assert_eq!(Parking::parking_lot_fee_index(parking_lot_hash), 0);
+
+            // the quote counts the entering user: 2 * 90 / 10 + 10 per second
+            assert_eq!(Parking::quote_fee(parking_lot_hash, SpotCategory::Standard, 10), Some(280));
+            assert_eq!(Parking::quote_fee(parking_lot_hash, SpotCategory::EvCharging, 10), None);
+
+            // the quote is the fee charged by leaving after the same stay, 10s are billed as a minute
+            let billing = Billing {
+                granularity: Granularity::Minute,
+                rounding: Rounding::Up,
+            };
+            assert_ok!(Parking::set_billing(Origin::signed(owner), parking_lot_hash, billing));
+            let quote = Parking::quote_fee(parking_lot_hash, SpotCategory::Standard, 10).unwrap();
+            assert_eq!(quote, 60 * 28);
+            let rich = 6;
+            let balance = balances::Module::<Test>::free_balance(rich);
+            assert_ok!(enter(rich, parking_lot_hash, SpotCategory::Standard));
+            timestamp::Module::<Test>::set_timestamp(10_000);
+            assert_ok!(Parking::leaving(Origin::signed(rich), None));
+            assert_eq!(balances::Module::<Test>::free_balance(rich), balance - quote);
+
+            // and the fee rules apply to it
+            let rules = FeeRules {
+                grace_period: 60_000,
+                session_cap: None,
+                daily_cap: None,
+            };
+            assert_ok!(Parking::set_fee_rules(Origin::signed(owner), parking_lot_hash, rules));
+            assert_eq!(Parking::quote_fee(parking_lot_hash, SpotCategory::Standard, 10), Some(0));
+        })
+    }
+
+    #[test]
+    fn test_lots_near() {
+        with_externalities(&mut new_test_ext(), || {
+            let owner = 2;
+            // about 111m, 222m and 1.1km to the north of the point
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 31_001_000, 121_000_000, 10, 10, 100));
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 31_002_000, 121_000_000, 10, 20, 100));
+            assert_ok!(Parking::new_parking_lot(Origin::signed(owner), 31_010_000, 121_000_000, 1, 10, 100));
+            let first = Parking::owner_parking_lots_array((owner, 0));
+            let second = Parking::owner_parking_lots_array((owner, 1));
+            let third = Parking::owner_parking_lots_array((owner, 2));
+
+            let center = GeoPoint {
+                latitude: 31_000_000,
+                longitude: 121_000_000,
+            };
+            let near = |radius, min_remain, max_price| {
+                Parking::lots_near(center, radius, 10, min_remain, max_price)
+                    .into_iter()
+                    .map(|(hash, _, distance)| (hash, distance))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(near(500, 0, None), vec![(first, 111), (second, 222)]);
+            assert_eq!(near(2000, 0, None), vec![(first, 111), (second, 222), (third, 1111)]);
+            assert_eq!(near(2000, 0, Some(10)), vec![(first, 111), (third, 1111)]);
+            assert_eq!(Parking::lots_near(center, 2000, 1, 0, None).len(), 1);
+
+            assert_ok!(enter(1, third, SpotCategory::Standard));
+            assert_eq!(near(2000, 1, None), vec![(first, 111), (second, 222)]);
+
+            // the index follows updates and removals
+            assert_ok!(Parking::update_parking_lot(Origin::signed(owner), first, 32_000_000, 121_000_000, 10, 10, 100));
+            assert_eq!(near(500, 0, None), vec![(second, 222)]);
+            assert_ok!(Parking::remove_parking_lot(Origin::signed(owner), second));
+            assert!(near(500, 0, None).is_empty());
+            assert!(Parking::lots_by_cell(center.cell()).is_empty());
         })
     }
 
@@ -507,7 +5379,7 @@ mod tests {
         with_externalities(&mut new_test_ext(), || {
             let user = 0;
             assert_err!(
-                Parking::leaving(Origin::signed(user)),
+                Parking::leaving(Origin::signed(user), None),
                 "User has not entered a parking lot"
             );
         })
@@ -152,6 +152,8 @@ fn testnet_genesis(
             authorities: initial_authorities.iter().map(|x| (x.2.clone(), 1)).collect(),
         }),
         parking: Some(ParkingConfig {
+            // (owner, remain, capacity, current price, min price, max price, latitude, longitude),
+            // coordinates are micro-degrees, see `GeoPoint`
             parking_lots: vec![
                 (get_from_seed::<AccountId>("Alice"),
                  3,
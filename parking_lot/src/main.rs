@@ -4,6 +4,7 @@
 #![warn(unused_extern_crates)]
 
 mod chain_spec;
+mod rpc;
 #[macro_use]
 mod service;
 mod cli;
@@ -0,0 +1,562 @@
+//! The `parking_*` RPC methods, backed by the `ParkingApi` runtime API.
+
+use codec::Decode;
+use futures::{Future, Sink, Stream};
+use jsonrpc_core::{Error, ErrorCode, Result};
+use jsonrpc_derive::rpc;
+use jsonrpc_pubsub::{typed::Subscriber, SubscriptionId};
+use log::warn;
+use primitives::{blake2_256, storage::StorageKey};
+use serde::{Deserialize, Serialize};
+use sr_primitives::{
+    generic::BlockId,
+    traits::{Block as BlockT, ProvideRuntimeApi},
+};
+use std::sync::Arc;
+use substrate_client::{blockchain::HeaderBackend, BlockchainEvents};
+use substrate_rpc::{Metadata, Subscriptions};
+use wx_node_runtime::{
+    geo::MICRO_DEGREES,
+    opaque::Block,
+    parking::{LotStatus, PriceCurve, SpotCategory, SpotPool},
+    AccountId, Balance, Hash, Moment, ParkingApi as ParkingRuntimeApi, ParkingInfo, ParkingLot,
+};
+
+/// RPC extensions of the node.
+pub type RpcExtension = jsonrpc_core::IoHandler<Metadata>;
+
+/// Error code of failed runtime api calls.
+const RUNTIME_ERROR: i64 = 1;
+
+/// Instantiate all RPC extensions of the node.
+pub fn create<C>(client: Arc<C>) -> RpcExtension
+where
+    C: ProvideRuntimeApi + HeaderBackend<Block> + BlockchainEvents<Block> + Send + Sync + 'static,
+    C::Api: ParkingRuntimeApi<Block>,
+{
+    // notifications are sent from the executor of the RPC server that accepted the subscription
+    let subscriptions = Subscriptions::new(Arc::new(tokio::executor::DefaultExecutor::current()));
+    let mut io = jsonrpc_core::IoHandler::default();
+    io.extend_with(ParkingApi::to_delegate(Parking::new(client, subscriptions)));
+    io
+}
+
+/// Storage key of a parking lot in `ParkingLots`.
+fn parking_lot_key(hash: &Hash) -> StorageKey {
+    let mut key = b"Parking ParkingLots".to_vec();
+    key.extend_from_slice(hash.as_ref());
+    StorageKey(blake2_256(&key).to_vec())
+}
+
+/// Price curve of a parking lot, balances are decimal strings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Pricing {
+    Linear,
+    Flat,
+    Stepped { steps: Vec<(u8, String)> },
+    Surge { threshold: u8, growth: u32 },
+}
+
+impl From<PriceCurve<Balance>> for Pricing {
+    fn from(curve: PriceCurve<Balance>) -> Self {
+        match curve {
+            PriceCurve::Linear => Pricing::Linear,
+            PriceCurve::Flat => Pricing::Flat,
+            PriceCurve::Stepped(steps) => Pricing::Stepped {
+                steps: steps.into_iter().map(|(percent, price)| (percent, price.to_string())).collect(),
+            },
+            PriceCurve::Surge { threshold, growth } => Pricing::Surge { threshold, growth },
+        }
+    }
+}
+
+/// A parking lot, prices are balances per second as decimal strings and coordinates are degrees.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Lot {
+    pub hash: Hash,
+    pub owner: AccountId,
+    pub capacity: u32,
+    pub remain: u32,
+    pub current_price: String,
+    pub min_price: String,
+    pub max_price: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub status: String,
+    pub pricing: Pricing,
+    /// Positions of the categories other than standard, they are included in `capacity` and `remain`
+    pub categories: Vec<Pool>,
+}
+
+impl Lot {
+    fn new(hash: Hash, parking_lot: ParkingLot) -> Self {
+        Lot {
+            hash,
+            owner: parking_lot.owner,
+            capacity: parking_lot.capacity,
+            remain: parking_lot.remain,
+            current_price: parking_lot.current_price.to_string(),
+            min_price: parking_lot.min_price.to_string(),
+            max_price: parking_lot.max_price.to_string(),
+            latitude: f64::from(parking_lot.location.latitude) / MICRO_DEGREES as f64,
+            longitude: f64::from(parking_lot.location.longitude) / MICRO_DEGREES as f64,
+            status: status_name(parking_lot.status).into(),
+            pricing: parking_lot.pricing.into(),
+            categories: parking_lot
+                .categories
+                .into_iter()
+                .map(|(category, pool)| Pool::new(category, pool))
+                .collect(),
+        }
+    }
+}
+
+/// Positions of a category in a parking lot, prices are balances per second as decimal strings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Pool {
+    pub category: String,
+    pub capacity: u32,
+    pub remain: u32,
+    pub current_price: String,
+    pub min_price: String,
+    pub max_price: String,
+}
+
+impl Pool {
+    fn new(category: SpotCategory, pool: SpotPool<Balance>) -> Self {
+        Pool {
+            category: category_name(category).into(),
+            capacity: pool.capacity,
+            remain: pool.remain,
+            current_price: pool.current_price.to_string(),
+            min_price: pool.min_price.to_string(),
+            max_price: pool.max_price.to_string(),
+        }
+    }
+}
+
+fn status_name(status: LotStatus) -> &'static str {
+    match status {
+        LotStatus::Open => "open",
+        LotStatus::Closed => "closed",
+        LotStatus::Maintenance => "maintenance",
+    }
+}
+
+fn category_name(category: SpotCategory) -> &'static str {
+    match category {
+        SpotCategory::Standard => "standard",
+        SpotCategory::EvCharging => "evCharging",
+        SpotCategory::Accessible => "accessible",
+        SpotCategory::Compact => "compact",
+        SpotCategory::Motorcycle => "motorcycle",
+    }
+}
+
+/// The category of a name given by `category_name`, the standard positions if there is no name
+fn parse_category(name: Option<String>) -> Result<SpotCategory> {
+    let categories = [
+        SpotCategory::Standard,
+        SpotCategory::EvCharging,
+        SpotCategory::Accessible,
+        SpotCategory::Compact,
+        SpotCategory::Motorcycle,
+    ];
+    match name {
+        Some(name) => categories
+            .iter()
+            .cloned()
+            .find(|category| category_name(*category) == name)
+            .ok_or_else(|| Error::invalid_params(format!("Unknown spot category {}", name))),
+        None => Ok(SpotCategory::Standard),
+    }
+}
+
+/// A parking session with the fee accrued until `currentTime`, times are milliseconds since the unix epoch
+/// and balances are decimal strings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub user: AccountId,
+    pub lot: Hash,
+    pub enter_time: Moment,
+    pub current_time: Moment,
+    pub parked_seconds: u64,
+    pub current_fee: String,
+    pub paid_fee: String,
+    pub deposit: String,
+    /// End of the booked window the user entered with
+    pub booked_until: Option<Moment>,
+    /// End of the time paid up front by a prepaid session
+    pub paid_until: Option<Moment>,
+    pub category: String,
+    /// Number of the spot assigned to the user if the spots of the parking lot are numbered
+    pub spot: Option<u32>,
+}
+
+impl From<ParkingInfo> for Session {
+    fn from(parking_info: ParkingInfo) -> Self {
+        Session {
+            user: parking_info.user_id,
+            lot: parking_info.parking_lot_hash,
+            enter_time: parking_info.enter_time,
+            current_time: parking_info.current_time,
+            parked_seconds: parking_info.current_time.saturating_sub(parking_info.enter_time) / 1000,
+            current_fee: parking_info.current_fee.to_string(),
+            paid_fee: parking_info.paid_fee.to_string(),
+            deposit: parking_info.deposit.to_string(),
+            booked_until: parking_info.booking.map(|(end, _)| end),
+            paid_until: parking_info.paid_until,
+            category: category_name(parking_info.category).into(),
+            spot: parking_info.spot,
+        }
+    }
+}
+
+/// A change of a parking lot in an imported block, `lot` is empty if it was removed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LotChange {
+    pub block: Hash,
+    pub hash: Hash,
+    pub lot: Option<Lot>,
+}
+
+/// Parking RPC methods.
+#[rpc]
+pub trait ParkingApi<BlockHash> {
+    /// RPC metadata
+    type Metadata;
+
+    /// The parking lot of the hash.
+    #[rpc(name = "parking_getLot")]
+    fn get_lot(&self, hash: Hash, at: Option<BlockHash>) -> Result<Option<Lot>>;
+
+    /// Parking lots in the order of their indexes, starting from `offset` and at most `limit` of them.
+    #[rpc(name = "parking_listLots")]
+    fn list_lots(&self, offset: u64, limit: u64, at: Option<BlockHash>) -> Result<Vec<Lot>>;
+
+    /// The parking session of the account with the fee accrued until the time of the block `at`, or of the best
+    /// block if there is no `at`.
+    #[rpc(name = "parking_getSession")]
+    fn get_session(&self, account: AccountId, at: Option<BlockHash>) -> Result<Option<Session>>;
+
+    /// Fee of parking `seconds` in a position of the category, standard if none, if entering now as a decimal
+    /// string. It is charged as leaving would, with the billing and the fee rules of the parking lot.
+    /// Nothing if the positions of the category are full or the parking lot has not existed.
+    #[rpc(name = "parking_quoteFee")]
+    fn quote_fee(
+        &self,
+        lot: Hash,
+        category: Option<String>,
+        seconds: u64,
+        at: Option<BlockHash>,
+    ) -> Result<Option<String>>;
+
+    /// All parking lots of the owner.
+    #[rpc(name = "parking_lotsOfOwner")]
+    fn lots_of_owner(&self, account: AccountId, at: Option<BlockHash>) -> Result<Vec<Lot>>;
+
+    /// Subscribe to the changes of the parking lot, e.g. its `remain` and `currentPrice`.
+    #[pubsub(subscription = "parking_lot", subscribe, name = "parking_subscribeLot")]
+    fn subscribe_lot(&self, metadata: Self::Metadata, subscriber: Subscriber<LotChange>, hash: Hash);
+
+    /// Unsubscribe from the changes of a parking lot.
+    #[pubsub(subscription = "parking_lot", unsubscribe, name = "parking_unsubscribeLot")]
+    fn unsubscribe_lot(&self, metadata: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool>;
+}
+
+/// Implementation of the parking RPC methods.
+pub struct Parking<C> {
+    client: Arc<C>,
+    subscriptions: Subscriptions,
+}
+
+impl<C> Parking<C> {
+    /// Create a new `Parking` with the given client and subscriptions manager.
+    pub fn new(client: Arc<C>, subscriptions: Subscriptions) -> Self {
+        Parking { client, subscriptions }
+    }
+}
+
+impl<C> Parking<C>
+where
+    C: HeaderBackend<Block>,
+{
+    fn block_id(&self, at: Option<<Block as BlockT>::Hash>) -> BlockId<Block> {
+        BlockId::hash(at.unwrap_or_else(|| self.client.info().best_hash))
+    }
+}
+
+fn runtime_error<E: std::fmt::Debug>(err: E) -> Error {
+    Error {
+        code: ErrorCode::ServerError(RUNTIME_ERROR),
+        message: "Runtime api call failed".into(),
+        data: Some(format!("{:?}", err).into()),
+    }
+}
+
+impl<C> ParkingApi<<Block as BlockT>::Hash> for Parking<C>
+where
+    C: ProvideRuntimeApi + HeaderBackend<Block> + BlockchainEvents<Block> + Send + Sync + 'static,
+    C::Api: ParkingRuntimeApi<Block>,
+{
+    type Metadata = Metadata;
+
+    fn get_lot(&self, hash: Hash, at: Option<<Block as BlockT>::Hash>) -> Result<Option<Lot>> {
+        let api = self.client.runtime_api();
+        let parking_lot = api.parking_lot(&self.block_id(at), hash).map_err(runtime_error)?;
+        Ok(parking_lot.map(|parking_lot| Lot::new(hash, parking_lot)))
+    }
+
+    fn list_lots(&self, offset: u64, limit: u64, at: Option<<Block as BlockT>::Hash>) -> Result<Vec<Lot>> {
+        let api = self.client.runtime_api();
+        let parking_lots = api.parking_lots(&self.block_id(at), offset, limit).map_err(runtime_error)?;
+        Ok(parking_lots.into_iter().map(|(hash, parking_lot)| Lot::new(hash, parking_lot)).collect())
+    }
+
+    fn get_session(&self, account: AccountId, at: Option<<Block as BlockT>::Hash>) -> Result<Option<Session>> {
+        let api = self.client.runtime_api();
+        let block_id = self.block_id(at);
+        // the fee is accrued until the timestamp of the block, so that the session agrees with its state
+        let now = api.now(&block_id).map_err(runtime_error)?;
+        let parking_info = api.parking_session(&block_id, account, now).map_err(runtime_error)?;
+        Ok(parking_info.map(Into::into))
+    }
+
+    fn quote_fee(
+        &self,
+        lot: Hash,
+        category: Option<String>,
+        seconds: u64,
+        at: Option<<Block as BlockT>::Hash>,
+    ) -> Result<Option<String>> {
+        let category = parse_category(category)?;
+        let api = self.client.runtime_api();
+        let fee = api.quote_fee(&self.block_id(at), lot, category, seconds).map_err(runtime_error)?;
+        Ok(fee.map(|fee| fee.to_string()))
+    }
+
+    fn lots_of_owner(&self, account: AccountId, at: Option<<Block as BlockT>::Hash>) -> Result<Vec<Lot>> {
+        let api = self.client.runtime_api();
+        let parking_lots = api.owner_parking_lots(&self.block_id(at), account).map_err(runtime_error)?;
+        Ok(parking_lots.into_iter().map(|(hash, parking_lot)| Lot::new(hash, parking_lot)).collect())
+    }
+
+    fn subscribe_lot(&self, _metadata: Self::Metadata, subscriber: Subscriber<LotChange>, hash: Hash) {
+        let key = parking_lot_key(&hash);
+        let stream = match self.client.storage_changes_notification_stream(Some(&[key.clone()]), None) {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = subscriber.reject(runtime_error(err));
+                return;
+            }
+        };
+
+        self.subscriptions.add(subscriber, move |sink| {
+            let changes = stream.filter_map(move |(block, changes)| {
+                let data = changes
+                    .iter()
+                    .find(|(child_key, changed_key, _)| child_key.is_none() && **changed_key == key)
+                    .map(|(_, _, data)| data.cloned())?;
+                let lot = data
+                    .and_then(|data| ParkingLot::decode(&mut &data.0[..]).ok())
+                    .map(|parking_lot| Lot::new(hash, parking_lot));
+                Some(Ok::<_, Error>(LotChange { block, hash, lot }))
+            });
+            sink.sink_map_err(|err| warn!("Error sending parking lot notifications: {:?}", err))
+                .send_all(changes)
+                .map(|_| ())
+        });
+    }
+
+    fn unsubscribe_lot(&self, _metadata: Option<Self::Metadata>, id: SubscriptionId) -> Result<bool> {
+        Ok(self.subscriptions.cancel(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chain_spec::Alternative, service::Executor};
+    use codec::Encode;
+    use primitives::{sr25519, Pair};
+    use serde_json::Value;
+    use sr_primitives::{generic::Era, traits::Header as HeaderT, BuildStorage, OpaqueExtrinsic};
+    use std::iter;
+    use test_client::{
+        consensus::BlockOrigin, ChildrenStorageOverlay, ClientExt, GenesisInit, NativeExecutor, StorageOverlay,
+        TestClientBuilder,
+    };
+    use tokio::runtime::Runtime;
+    use wx_node_runtime::{
+        parking::GuardAction, Address, Call, Index, RuntimeApi, SignedExtra, TimestampCall, UncheckedExtrinsic,
+        VERSION,
+    };
+
+    /// Client of the development chain executing the native runtime
+    type Client = substrate_client::Client<
+        test_client::Backend<Block>,
+        substrate_client::LocalCallExecutor<test_client::Backend<Block>, NativeExecutor<Executor>>,
+        Block,
+        RuntimeApi,
+    >;
+
+    /// Genesis of the development chain, Alice owns its four parking lots
+    #[derive(Default)]
+    struct Genesis;
+
+    impl GenesisInit for Genesis {
+        fn genesis_storage(&self) -> (StorageOverlay, ChildrenStorageOverlay) {
+            Alternative::Development
+                .load()
+                .and_then(|chain_spec| chain_spec.build_storage())
+                .expect("the development chain spec is valid")
+        }
+    }
+
+    fn new_client() -> Arc<Client> {
+        let builder = TestClientBuilder::<_, test_client::Backend<Block>, Genesis>::with_default_backend();
+        let (client, _): (Client, _) = builder.build_with_native_executor(None);
+        Arc::new(client)
+    }
+
+    fn pair(seed: &str) -> sr25519::Pair {
+        sr25519::Pair::from_string(&format!("//{}", seed), None).expect("static values are valid; qed")
+    }
+
+    /// The call signed by the key with its nonce, valid on the chain of the client
+    fn sign(client: &Client, key: &sr25519::Pair, nonce: Index, call: Call) -> OpaqueExtrinsic {
+        let genesis = client.genesis_hash();
+        let extra: SignedExtra = (
+            system::CheckVersion::new(),
+            system::CheckGenesis::new(),
+            system::CheckEra::from(Era::Immortal),
+            system::CheckNonce::from(nonce),
+            system::CheckWeight::new(),
+            balances::TakeFees::from(0),
+        );
+        let additional_signed = (VERSION.spec_version, genesis, genesis, (), (), ());
+        let signature = (&call, &extra, &additional_signed).using_encoded(|payload| {
+            if payload.len() > 256 {
+                key.sign(&blake2_256(payload))
+            } else {
+                key.sign(payload)
+            }
+        });
+        opaque(UncheckedExtrinsic::new_signed(call, Address::Id(key.public()), signature.into(), extra))
+    }
+
+    fn opaque(extrinsic: UncheckedExtrinsic) -> OpaqueExtrinsic {
+        Decode::decode(&mut &extrinsic.encode()[..]).expect("an extrinsic is an opaque extrinsic")
+    }
+
+    /// Entering a standard position of the parking lot without a price guard
+    fn entering(hash: Hash) -> Call {
+        Call::Parking(wx_node_runtime::parking::Call::entering(
+            hash,
+            SpotCategory::Standard,
+            Balance::max_value(),
+            None,
+            GuardAction::Ignore,
+            None,
+        ))
+    }
+
+    /// Import a block of the time with the extrinsics following its timestamp, returning the hash of the block
+    fn import_block(client: &Client, now: Moment, extrinsics: Vec<OpaqueExtrinsic>) -> Hash {
+        let mut builder = client.new_block(Default::default()).expect("the best block can be built on");
+        let timestamp = opaque(UncheckedExtrinsic::new_unsigned(Call::Timestamp(TimestampCall::set(now))));
+        for extrinsic in iter::once(timestamp).chain(extrinsics) {
+            builder.push(extrinsic).expect("the extrinsic can be applied");
+        }
+        let block = builder.bake().expect("the block can be baked");
+        let hash = block.header.hash();
+        client.import(BlockOrigin::Own, block).expect("the block can be imported");
+        hash
+    }
+
+    #[test]
+    fn test_queries() {
+        let core = Runtime::new().unwrap();
+        let client = new_client();
+        let api = Parking::new(client.clone(), Subscriptions::new(Arc::new(core.executor())));
+        let alice = pair("Alice").public();
+        let bob = pair("Bob");
+
+        let lots = api.list_lots(0, 10, None).unwrap();
+        assert_eq!(lots.len(), 4);
+        let lot = lots[0].clone();
+        assert_eq!(lot.owner, alice);
+        assert_eq!((lot.capacity, lot.remain), (3, 3));
+        assert_eq!((lot.current_price.as_str(), lot.min_price.as_str(), lot.max_price.as_str()), ("5", "5", "100"));
+        assert_eq!((lot.latitude, lot.longitude), (31.247538, 121.489559));
+        assert_eq!(lot.status, "open");
+        assert_eq!(lot.pricing, Pricing::Linear);
+        assert!(lot.categories.is_empty());
+        assert_eq!(api.list_lots(1, 2, None).unwrap(), lots[1..3].to_vec());
+        assert!(api.list_lots(4, 10, None).unwrap().is_empty());
+
+        assert_eq!(api.get_lot(lot.hash, None).unwrap(), Some(lot.clone()));
+        assert_eq!(api.get_lot(Hash::default(), None).unwrap(), None);
+        assert_eq!(api.lots_of_owner(alice, None).unwrap(), lots);
+        assert!(api.lots_of_owner(bob.public(), None).unwrap().is_empty());
+
+        // the quote counts the entering user: 1 * 95 / 3 + 5 per second
+        assert_eq!(api.quote_fee(lot.hash, None, 10, None).unwrap(), Some("360".into()));
+        assert_eq!(api.quote_fee(lot.hash, Some("standard".into()), 10, None).unwrap(), Some("360".into()));
+        assert_eq!(api.quote_fee(lot.hash, Some("evCharging".into()), 10, None).unwrap(), None);
+        assert!(api.quote_fee(lot.hash, Some("bicycle".into()), 10, None).is_err());
+
+        assert_eq!(api.get_session(bob.public(), None).unwrap(), None);
+        let genesis = client.genesis_hash();
+        let entered = import_block(&client, 10_000, vec![sign(&client, &bob, 0, entering(lot.hash))]);
+        let session = api.get_session(bob.public(), None).unwrap().unwrap();
+        assert_eq!(session.lot, lot.hash);
+        assert_eq!((session.enter_time, session.current_time), (10_000, 10_000));
+        assert_eq!(session.current_fee, "0");
+        assert_eq!(session.category, "standard");
+
+        // the session is accrued until the timestamp of the best block
+        import_block(&client, 20_000, vec![]);
+        let session = api.get_session(bob.public(), None).unwrap().unwrap();
+        assert_eq!((session.current_time, session.parked_seconds), (20_000, 10));
+        assert_eq!(session.current_fee, "360");
+        let session = api.get_session(bob.public(), Some(entered)).unwrap().unwrap();
+        assert_eq!((session.current_time, session.current_fee.as_str()), (10_000, "0"));
+
+        assert_eq!(api.get_lot(lot.hash, None).unwrap().unwrap().remain, 2);
+        assert_eq!(api.get_lot(lot.hash, Some(genesis)).unwrap(), Some(lot.clone()));
+        assert_eq!(api.quote_fee(lot.hash, None, 10, None).unwrap(), Some("680".into()));
+        assert_eq!(api.quote_fee(lot.hash, None, 10, Some(genesis)).unwrap(), Some("360".into()));
+    }
+
+    #[test]
+    fn test_subscribe_lot() {
+        let mut core = Runtime::new().unwrap();
+        let client = new_client();
+        let api = Parking::new(client.clone(), Subscriptions::new(Arc::new(core.executor())));
+        let lots = api.list_lots(0, 2, None).unwrap();
+
+        let (subscriber, id, transport) = Subscriber::new_test("test");
+        api.subscribe_lot(Default::default(), subscriber, lots[0].hash);
+        assert_eq!(core.block_on(id), Ok(Ok(SubscriptionId::Number(1))));
+
+        // entering the other parking lot does not change the key of the subscribed one
+        import_block(&client, 10_000, vec![sign(&client, &pair("Bob"), 0, entering(lots[1].hash))]);
+        let block = import_block(&client, 20_000, vec![sign(&client, &pair("Charlie"), 0, entering(lots[0].hash))]);
+
+        let (notification, _) = core.block_on(transport.into_future()).unwrap();
+        let notification: Value = serde_json::from_str(&notification.unwrap()).unwrap();
+        let change: LotChange = serde_json::from_value(notification["params"]["result"].clone()).unwrap();
+        assert_eq!((change.block, change.hash), (block, lots[0].hash));
+        let lot = change.lot.unwrap();
+        assert_eq!((lot.remain, lot.current_price.as_str()), (2, "36"));
+
+        assert_eq!(api.unsubscribe_lot(None, SubscriptionId::Number(1)), Ok(true));
+        assert_eq!(api.unsubscribe_lot(None, SubscriptionId::Number(1)), Ok(false));
+    }
+}
@@ -74,7 +74,8 @@ macro_rules! new_full_start {
             tasks_to_spawn = Some(vec![Box::new(pruning_task)]);
 
             Ok(import_queue)
-        })?;
+        })?
+        .with_rpc_extensions(|client, _pool| -> crate::rpc::RpcExtension { crate::rpc::create(client) })?;
 
         (builder, import_setup, inherent_data_providers, tasks_to_spawn)
     }};
@@ -222,6 +223,7 @@ pub fn new_light<C: Send + Default + 'static>(
 
             Ok((import_queue, finality_proof_request_builder))
         })?
+        .with_rpc_extensions(|client, _pool| -> crate::rpc::RpcExtension { crate::rpc::create(client) })?
         .with_network_protocol(|_| Ok(NodeProtocol::new()))?
         .with_finality_proof_provider(|client, backend| {
             Ok(Arc::new(GrandpaFinalityProofProvider::new(backend, client)) as _)